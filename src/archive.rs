@@ -0,0 +1,403 @@
+use std::fs;
+use std::io::{self, Cursor, ErrorKind, Read};
+use std::path::{Component, Path, PathBuf};
+use flate2::read::GzDecoder;
+use tar::Archive;
+use tracing::debug;
+use xz2::read::XzDecoder;
+use zip::ZipArchive;
+
+/// Formats a generator archive can be packaged in, detected from the first bytes of the archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+    TarXz,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(b"PK\x03\x04") || bytes.starts_with(b"PK\x05\x06") {
+            Some(ArchiveFormat::Zip)
+        } else if bytes.starts_with(&[0x1f, 0x8b]) {
+            Some(ArchiveFormat::TarGz)
+        } else if bytes.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(ArchiveFormat::TarXz)
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(ArchiveFormat::TarZst)
+        } else if bytes.get(257..262) == Some(b"ustar") {
+            Some(ArchiveFormat::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+enum EntryKind {
+    Directory,
+    File(Vec<u8>),
+    /// a symbolic link with its target, relative to the directory of the link
+    Symlink(PathBuf),
+    /// a hard link with the path of the linked entry in the archive
+    HardLink(PathBuf),
+}
+
+struct Entry {
+    path: PathBuf,
+    kind: EntryKind,
+}
+
+/// Extracts the archive in `bytes` into `destination`.
+/// When every entry is nested in the same top-level directory, as in archives created by GitHub or `git archive`,
+/// that directory is stripped so the content ends up directly in `destination`.
+/// Entries with absolute paths, entries escaping `destination` and symbolic links pointing outside of it are rejected.
+pub fn extract(bytes: &[u8], destination: &Path) -> Result<(), io::Error> {
+    let format = ArchiveFormat::detect(bytes)
+        .ok_or_else(|| invalid("Unsupported archive format, expected zip, tar, tar.gz, tar.xz or tar.zst".to_string()))?;
+    debug!("Extracting {:?} archive into {}", format, destination.display());
+    let entries = match format {
+        ArchiveFormat::Zip => read_zip(bytes)?,
+        ArchiveFormat::Tar => read_tar(bytes)?,
+        ArchiveFormat::TarGz => read_tar(&decompress(GzDecoder::new(bytes))?)?,
+        ArchiveFormat::TarXz => read_tar(&decompress(XzDecoder::new(bytes))?)?,
+        ArchiveFormat::TarZst => read_tar(&decompress(zstd::stream::read::Decoder::new(bytes)?)?)?,
+    };
+
+    let top_level_dir = common_top_level_dir(&entries);
+    if let Some(top_level_dir) = &top_level_dir {
+        debug!("Stripping top-level directory {}", top_level_dir.display());
+    }
+    let strip = |path: &Path| -> Option<PathBuf> {
+        let relative = match &top_level_dir {
+            Some(top_level_dir) => path.strip_prefix(top_level_dir).ok()?,
+            None => path,
+        };
+        (relative.components().next().is_some()).then(|| relative.to_path_buf())
+    };
+
+    fs::create_dir_all(destination)?;
+    for entry in &entries {
+        let Some(relative) = strip(&entry.path) else {
+            continue;
+        };
+        check_no_symlinks(destination, &relative)?;
+        let target = destination.join(&relative);
+        if let (Some(parent), false) = (target.parent(), matches!(entry.kind, EntryKind::Directory)) {
+            fs::create_dir_all(parent)?;
+        }
+        match &entry.kind {
+            EntryKind::Directory => fs::create_dir_all(&target)?,
+            EntryKind::File(content) => fs::write(&target, content)?,
+            EntryKind::Symlink(link) => {
+                check_symlink(&relative, link)?;
+                create_symlink(link, &target)?;
+            }
+            EntryKind::HardLink(link) => {
+                let source = strip(link)
+                    .ok_or_else(|| invalid(format!("Hard link {} points outside of the archive", entry.path.display())))?;
+                check_no_symlinks(destination, &source)?;
+                fs::copy(destination.join(&source), &target)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_zip(bytes: &[u8]) -> Result<Vec<Entry>, io::Error> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+    let mut entries = Vec::new();
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let name = file.name().to_string();
+        let kind = if file.is_dir() {
+            EntryKind::Directory
+        } else {
+            let mut content = Vec::new();
+            file.read_to_end(&mut content)?;
+            if file.unix_mode().is_some_and(|mode| mode & 0o170000 == 0o120000) {
+                let link = String::from_utf8(content)
+                    .map_err(|_| invalid(format!("Symbolic link {} has an invalid target", name)))?;
+                EntryKind::Symlink(PathBuf::from(link))
+            } else {
+                EntryKind::File(content)
+            }
+        };
+        if let Some(path) = entry_path(Path::new(&name))? {
+            entries.push(Entry { path, kind });
+        }
+    }
+    Ok(entries)
+}
+
+fn read_tar(bytes: &[u8]) -> Result<Vec<Entry>, io::Error> {
+    let mut archive = Archive::new(bytes);
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        let name = entry.path()?.into_owned();
+        let link = || -> Result<PathBuf, io::Error> {
+            Ok(entry.link_name()?
+                .ok_or_else(|| invalid(format!("Link {} has no target", name.display())))?
+                .into_owned())
+        };
+        let kind = if entry_type.is_dir() {
+            EntryKind::Directory
+        } else if entry_type.is_symlink() {
+            EntryKind::Symlink(link()?)
+        } else if entry_type.is_hard_link() {
+            let link = link()?;
+            EntryKind::HardLink(entry_path(&link)?
+                .ok_or_else(|| invalid(format!("Hard link {} has no target", name.display())))?)
+        } else if entry_type.is_file() || entry_type.is_contiguous() {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            EntryKind::File(content)
+        } else {
+            debug!("Skipping {:?} entry {}", entry_type, name.display());
+            continue;
+        };
+        if let Some(path) = entry_path(&name)? {
+            entries.push(Entry { path, kind });
+        }
+    }
+    Ok(entries)
+}
+
+fn decompress(mut reader: impl Read) -> Result<Vec<u8>, io::Error> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Normalizes the path of an entry, rejecting absolute paths and paths with `..`.
+/// Returns `None` for the root of the archive itself, e.g. `./`.
+fn entry_path(name: &Path) -> Result<Option<PathBuf>, io::Error> {
+    let mut path = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) =>
+                return Err(invalid(format!("Entry {} escapes the target directory", name.display()))),
+        }
+    }
+    Ok((!path.as_os_str().is_empty()).then_some(path))
+}
+
+/// The directory every entry is nested in, if there is one.
+/// A single file at the top level is content of the archive, not a directory to strip.
+fn common_top_level_dir(entries: &[Entry]) -> Option<PathBuf> {
+    let top_level = entries.first()?.path.components().next()?;
+    let shared = entries.iter().all(|entry| {
+        let mut components = entry.path.components();
+        components.next() == Some(top_level) && (components.next().is_some() || matches!(entry.kind, EntryKind::Directory))
+    });
+    shared.then(|| PathBuf::from(top_level.as_os_str()))
+}
+
+/// Refuses to write `relative` through a symbolic link extracted earlier, which could lead outside of `destination`.
+fn check_no_symlinks(destination: &Path, relative: &Path) -> Result<(), io::Error> {
+    let mut path = destination.to_path_buf();
+    for component in relative.components() {
+        path.push(component);
+        if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            return Err(invalid(format!("Entry {} would be written through the symbolic link {}", relative.display(), path.display())));
+        }
+    }
+    Ok(())
+}
+
+/// A symbolic link is safe when its target is relative and stays below the extraction directory.
+/// `..` is only accepted at the start of the target: there it climbs the real directories containing the link,
+/// after a symbolic link component it could climb anywhere.
+fn check_symlink(relative: &Path, link: &Path) -> Result<(), io::Error> {
+    let mut depth = relative.components().count() - 1;
+    let mut descended = false;
+    for component in link.components() {
+        match component {
+            Component::Normal(_) => {
+                depth += 1;
+                descended = true;
+            }
+            Component::CurDir => {}
+            Component::ParentDir if !descended && depth > 0 => depth -= 1,
+            _ => return Err(invalid(format!("Symbolic link {} -> {} points outside of the archive", relative.display(), link.display()))),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(link: &Path, target: &Path) -> Result<(), io::Error> {
+    std::os::unix::fs::symlink(link, target)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(link: &Path, target: &Path) -> Result<(), io::Error> {
+    Err(io::Error::new(ErrorKind::Unsupported, format!("Cannot create symbolic link {} -> {}", target.display(), link.display())))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tar::{EntryType, Header};
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    /// A tar archive with raw entry names, so that names the `tar` builder refuses can be written too.
+    fn tar(entries: &[(&str, EntryType, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, entry_type, content) in entries {
+            let mut header = Header::new_gnu();
+            let gnu = header.as_gnu_mut().unwrap();
+            gnu.name[..name.len()].copy_from_slice(name.as_bytes());
+            let (data, link) = match entry_type {
+                EntryType::Symlink | EntryType::Link => ("", *content),
+                _ => (*content, ""),
+            };
+            gnu.linkname[..link.len()].copy_from_slice(link.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, data.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn tar_gz(entries: &[(&str, EntryType, &str)]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&tar(entries)).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn extract_into(bytes: &[u8]) -> (tempfile::TempDir, Result<(), io::Error>) {
+        let dir = tempfile::tempdir().unwrap();
+        let result = extract(bytes, &dir.path().join("out"));
+        (dir, result)
+    }
+
+    #[test]
+    fn strips_the_top_level_directory() {
+        let bytes = tar_gz(&[
+            ("gen/", EntryType::Directory, ""),
+            ("gen/Generator.yaml", EntryType::Regular, "name: gen"),
+            ("gen/templates/a.tera", EntryType::Regular, "a"),
+        ]);
+        let (dir, result) = extract_into(&bytes);
+        result.unwrap();
+        let out = dir.path().join("out");
+        assert_eq!(fs::read_to_string(out.join("Generator.yaml")).unwrap(), "name: gen");
+        assert_eq!(fs::read_to_string(out.join("templates/a.tera")).unwrap(), "a");
+    }
+
+    #[test]
+    fn keeps_content_without_a_shared_directory() {
+        let bytes = zip(&[("Generator.yaml", "name: gen"), ("templates/a.tera", "a")]);
+        let (dir, result) = extract_into(&bytes);
+        result.unwrap();
+        assert!(dir.path().join("out/Generator.yaml").is_file());
+        assert!(dir.path().join("out/templates/a.tera").is_file());
+    }
+
+    #[test]
+    fn rejects_parent_directory_entries() {
+        let (dir, result) = extract_into(&tar(&[("gen/../../evil", EntryType::Regular, "x")]));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(!dir.path().join("evil").exists());
+
+        let (dir, result) = extract_into(&zip(&[("../evil", "x")]));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(!dir.path().join("evil").exists());
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        let (_dir, result) = extract_into(&tar(&[("/tmp/evil", EntryType::Regular, "x")]));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let (_dir, result) = extract_into(&zip(&[("/tmp/evil", "x")]));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn accepts_symlinks_inside_the_archive() {
+        let (dir, result) = extract_into(&tar(&[
+            ("a/target.txt", EntryType::Regular, "x"),
+            ("link", EntryType::Symlink, "a/target.txt"),
+            ("a/up", EntryType::Symlink, "../link"),
+        ]));
+        result.unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("out/link")).unwrap(), "x");
+    }
+
+    #[test]
+    fn rejects_symlinks_outside_of_the_archive() {
+        for target in ["../outside", "/etc/passwd", "a/../../outside", "a/../../../outside"] {
+            let (_dir, result) = extract_into(&tar(&[
+                ("a/file", EntryType::Regular, "x"),
+                ("link", EntryType::Symlink, target),
+            ]));
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData, "{} should be rejected", target);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn rejects_writing_through_symlinks() {
+        let (dir, result) = extract_into(&tar(&[
+            ("a/file", EntryType::Regular, "x"),
+            ("b", EntryType::Symlink, "a"),
+            ("b/evil", EntryType::Regular, "x"),
+        ]));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(!dir.path().join("out/a/evil").exists());
+    }
+
+    #[test]
+    fn copies_hard_links_inside_the_archive() {
+        let (dir, result) = extract_into(&tar(&[
+            ("a/file", EntryType::Regular, "x"),
+            ("b/link", EntryType::Link, "a/file"),
+        ]));
+        result.unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("out/b/link")).unwrap(), "x");
+    }
+
+    #[test]
+    fn rejects_hard_links_outside_of_the_archive() {
+        for target in ["../outside", "/etc/passwd"] {
+            let (_dir, result) = extract_into(&tar(&[
+                ("a/file", EntryType::Regular, "x"),
+                ("b/link", EntryType::Link, target),
+            ]));
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData, "{} should be rejected", target);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        let (_dir, result) = extract_into(b"not an archive");
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
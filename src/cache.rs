@@ -0,0 +1,470 @@
+use std::{fs, io};
+use std::cmp::Reverse;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+use crate::archive;
+use crate::config::FetchPolicy;
+use crate::git::GitSource;
+use crate::lock::bytes_digest;
+use crate::remote::read_url;
+
+/// Directory in the local data directory with the downloaded archives, their extracted generators and git checkouts.
+const DOWNLOADS_DIR: &str = "cache/downloads";
+/// Archives by the hex of their sha256 digest.
+const BLOBS_DIR: &str = "blobs";
+/// Extracted archives by the hex of their sha256 digest.
+const EXTRACTED_DIR: &str = "extracted";
+/// What was downloaded from a url, by the hex of the sha256 of the url.
+const URLS_DIR: &str = "urls";
+/// Checkouts of git sources by url and commit.
+const GIT_DIR: &str = "git";
+
+/// A url in the cache: the digest of its content and the validators to revalidate it with.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheEntry {
+    #[serde(rename = "url")]
+    pub url: Url,
+
+    /// sha256 digest of the content, the name of its blob
+    #[serde(rename = "digest")]
+    pub digest: String,
+
+    #[serde(rename = "etag", skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+
+    #[serde(rename = "last_modified", skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+
+    /// size of the content in bytes
+    #[serde(rename = "size")]
+    pub size: u64,
+
+    /// when the url was last used, in seconds since the unix epoch
+    #[serde(rename = "last_used")]
+    pub last_used: u64,
+}
+
+fn downloads_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join(DOWNLOADS_DIR)
+}
+
+fn hex_of(digest: &str) -> &str {
+    digest.trim_start_matches("sha256:")
+}
+
+fn url_entry_path(data_dir: &Path, url: &Url) -> PathBuf {
+    let key = hex::encode(Sha256::digest(url.as_str().as_bytes()));
+    downloads_dir(data_dir).join(URLS_DIR).join(format!("{}.yaml", key))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+fn read_entry(path: &Path) -> Result<CacheEntry, io::Error> {
+    serde_yaml::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("Cannot deserialize file {:?} due to error:{:?}", path, e)))
+}
+
+fn write_entry(path: &Path, entry: &CacheEntry) -> Result<(), io::Error> {
+    let content = serde_yaml::to_string(entry)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+    fs::write(path, content)
+}
+
+/// Reads a url through the download cache. `file://` urls are read directly.
+/// A cached `http(s)://` url is revalidated with its `ETag` and `Last-Modified` and only downloaded again when it changed.
+/// With `--offline` only the cache is used.
+pub async fn read_cached(policy: &FetchPolicy, url: &Url) -> Result<Vec<u8>, io::Error> {
+    if url.scheme() == "file" {
+        return read_url(url).await;
+    }
+    let entry_path = url_entry_path(&policy.data_dir, url);
+    let cached = read_entry(&entry_path).ok()
+        .and_then(|entry| {
+            let blob = downloads_dir(&policy.data_dir).join(BLOBS_DIR).join(hex_of(&entry.digest));
+            fs::read(blob).ok()
+                .filter(|bytes| bytes_digest(bytes) == entry.digest)
+                .map(|bytes| (entry, bytes))
+        });
+
+    if policy.offline {
+        let (mut entry, bytes) = cached
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("{} is not in the download cache and --offline is set", url)))?;
+        debug!("Using cached {} ({}) offline", url, entry.digest);
+        entry.last_used = now();
+        write_entry(&entry_path, &entry)?;
+        return Ok(bytes);
+    }
+
+    let mut request = reqwest::Client::new().get(url.clone());
+    if let Some((entry, _)) = &cached {
+        if let Some(etag) = &entry.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &entry.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let response = request.send().await
+        .map_err(|e| io::Error::other(format!("Failed to download file {} due to error: {}", url, e)))?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        if let Some((mut entry, bytes)) = cached {
+            debug!("{} is not modified, using cached {}", url, entry.digest);
+            entry.last_used = now();
+            write_entry(&entry_path, &entry)?;
+            return Ok(bytes);
+        }
+    }
+    if !response.status().is_success() {
+        return Err(io::Error::other(format!("Failed to download file from {}. Status code: {}", url, response.status())));
+    }
+    let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+    let etag = header(ETAG);
+    let last_modified = header(LAST_MODIFIED);
+    let bytes = response.bytes().await
+        .map_err(|e| io::Error::other(format!("Failed to read response bytes of file downloaded from url {} due to error: {}", url, e)))?
+        .to_vec();
+
+    let digest = bytes_digest(&bytes);
+    let blobs_dir = downloads_dir(&policy.data_dir).join(BLOBS_DIR);
+    fs::create_dir_all(&blobs_dir)?;
+    let blob = blobs_dir.join(hex_of(&digest));
+    if !blob.is_file() {
+        write_atomically(&blobs_dir, &blob, &bytes)?;
+    }
+    let entry = CacheEntry { url: url.clone(), digest, etag, last_modified, size: bytes.len() as u64, last_used: now() };
+    write_entry(&entry_path, &entry)?;
+    info!("Downloaded {} into the cache as {}", url, entry.digest);
+    Ok(bytes)
+}
+
+fn write_atomically(dir: &Path, path: &Path, bytes: &[u8]) -> Result<(), io::Error> {
+    let staging = tempfile::Builder::new().prefix(".staging-").tempfile_in(dir)?;
+    fs::write(staging.path(), bytes)?;
+    staging.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Extracts an archive once into the cache, keyed by its digest, and returns the directory it was extracted into.
+pub fn extract_cached(data_dir: &Path, bytes: &[u8]) -> Result<PathBuf, io::Error> {
+    let digest = bytes_digest(bytes);
+    let extracted_dir = downloads_dir(data_dir).join(EXTRACTED_DIR);
+    let destination = extracted_dir.join(hex_of(&digest));
+    if destination.is_dir() {
+        debug!("Using extracted {} in {}", digest, destination.display());
+        return Ok(destination);
+    }
+    fs::create_dir_all(&extracted_dir)?;
+    let staging = tempfile::Builder::new().prefix(".staging-").tempdir_in(&extracted_dir)?;
+    archive::extract(bytes, staging.path())?;
+    // another run may have extracted the same archive in the meantime
+    if let Err(e) = fs::rename(staging.path(), &destination) {
+        if !destination.is_dir() {
+            return Err(e);
+        }
+    }
+    Ok(destination)
+}
+
+/// Checks out a git source into the cache, keyed by its url and the commit that was checked out.
+/// A source pinned to a full commit with `rev=` is only cloned when that commit is not in the cache yet,
+/// which is also what `--offline` allows. Returns the directory of the generator and the commit.
+pub fn checkout_cached(policy: &FetchPolicy, source: &GitSource, base_path: &Path) -> Result<(PathBuf, String), io::Error> {
+    let git_dir = downloads_dir(&policy.data_dir).join(GIT_DIR);
+    let url_key = hex::encode(Sha256::digest(source.url.as_bytes()));
+    let checkout_dir = |commit: &str| git_dir.join(format!("{}-{}", &url_key[..16], commit));
+
+    if let Some(commit) = source.revision.as_deref().filter(|revision| is_full_commit(revision)) {
+        let commit = commit.to_lowercase();
+        let destination = checkout_dir(&commit);
+        if destination.is_dir() {
+            debug!("Using cached checkout of {} at {}", source.url, commit);
+            touch(&destination)?;
+            return Ok((generator_dir(source, &destination, &commit)?, commit));
+        }
+    }
+    // a local repository is cloned offline as well
+    if policy.offline && !source.url.starts_with("file://") {
+        return Err(io::Error::new(ErrorKind::NotFound,
+            format!("{} is not in the download cache and --offline is set, only sources pinned with rev=<full commit> are cached", source.url)));
+    }
+    fs::create_dir_all(&git_dir)?;
+    let staging = tempfile::Builder::new().prefix(".staging-").tempdir_in(&git_dir)?;
+    let (_, commit) = source.checkout(base_path, staging.path())?;
+
+    let destination = checkout_dir(&commit);
+    if let Err(e) = fs::rename(staging.path(), &destination) {
+        if !destination.is_dir() {
+            return Err(e);
+        }
+    }
+    touch(&destination)?;
+    Ok((generator_dir(source, &destination, &commit)?, commit))
+}
+
+/// Whether a `rev=` is a full commit id rather than an abbreviated one or another revision expression.
+fn is_full_commit(revision: &str) -> bool {
+    revision.len() == 40 && revision.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn generator_dir(source: &GitSource, checkout: &Path, commit: &str) -> Result<PathBuf, io::Error> {
+    let Some(subdirectory) = &source.subdirectory else {
+        return Ok(checkout.to_path_buf());
+    };
+    let path = checkout.join(subdirectory);
+    if !path.is_dir() {
+        return Err(io::Error::new(ErrorKind::NotFound, format!("Directory {} does not exist in {} at commit {}",
+            subdirectory.display(), source.url, commit)));
+    }
+    Ok(path)
+}
+
+/// Marks a checkout as used, so that pruning goes by its last use.
+fn touch(path: &Path) -> Result<(), io::Error> {
+    fs::File::open(path)?.set_modified(SystemTime::now())
+}
+
+/// Lists the urls in the download cache, the most recently used first.
+pub fn cache_entries(data_dir: &Path) -> Result<Vec<CacheEntry>, io::Error> {
+    let urls_dir = downloads_dir(data_dir).join(URLS_DIR);
+    if !urls_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    for file in fs::read_dir(&urls_dir)? {
+        let path = file?.path();
+        match read_entry(&path) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => debug!("Skipping {}: {}", path.display(), e),
+        }
+    }
+    entries.sort_by_key(|entry| Reverse(entry.last_used));
+    Ok(entries)
+}
+
+/// Removes the urls that were not used for `max_age`, the archives and extracted archives that no url refers to
+/// and that are older than `max_age`, and the git checkouts older than `max_age`. Returns the number of bytes freed.
+pub fn prune(data_dir: &Path, max_age: Duration) -> Result<u64, io::Error> {
+    let downloads_dir = downloads_dir(data_dir);
+    let oldest = now().saturating_sub(max_age.as_secs());
+    let mut freed = 0;
+    let mut kept = Vec::new();
+    let urls_dir = downloads_dir.join(URLS_DIR);
+    if urls_dir.is_dir() {
+        for file in fs::read_dir(&urls_dir)? {
+            let path = file?.path();
+            match read_entry(&path) {
+                Ok(entry) if entry.last_used > oldest => kept.push(hex_of(&entry.digest).to_string()),
+                _ => {
+                    debug!("Removing {}", path.display());
+                    freed += remove(&path)?;
+                }
+            }
+        }
+    }
+    for dir in [BLOBS_DIR, EXTRACTED_DIR] {
+        let dir = downloads_dir.join(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(&dir)? {
+            let path = file?.path();
+            let referenced = kept.iter().any(|digest| path.file_name().is_some_and(|name| name == digest.as_str()));
+            if !referenced && modified_before(&path, oldest)? {
+                debug!("Removing {}", path.display());
+                freed += remove(&path)?;
+            }
+        }
+    }
+    // checkouts are touched whenever they are used
+    let git_dir = downloads_dir.join(GIT_DIR);
+    if git_dir.is_dir() {
+        for file in fs::read_dir(&git_dir)? {
+            let path = file?.path();
+            if modified_before(&path, oldest)? {
+                debug!("Removing {}", path.display());
+                freed += remove(&path)?;
+            }
+        }
+    }
+    Ok(freed)
+}
+
+fn modified_before(path: &Path, oldest: u64) -> Result<bool, io::Error> {
+    let modified = fs::symlink_metadata(path)?.modified()?;
+    Ok(modified.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0) <= oldest)
+}
+
+/// Removes the whole download cache, returning the number of bytes freed.
+/// The cached indexes of the remote repositories are kept.
+pub fn clear(data_dir: &Path) -> Result<u64, io::Error> {
+    let downloads_dir = downloads_dir(data_dir);
+    if !downloads_dir.exists() {
+        return Ok(0);
+    }
+    remove(&downloads_dir)
+}
+
+/// Removes a file or directory and returns its size.
+fn remove(path: &Path) -> Result<u64, io::Error> {
+    let size = size_of(path)?;
+    if path.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(size)
+}
+
+fn size_of(path: &Path) -> Result<u64, io::Error> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += size_of(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+    use crate::config::UserConfig;
+    use crate::signing::Keyring;
+
+    fn policy(data_dir: &Path, offline: bool) -> FetchPolicy {
+        FetchPolicy { offline, ..FetchPolicy::new(data_dir, &UserConfig::default(), false, Keyring::default()) }
+    }
+
+    /// Serves one response per connection in order and returns the url and, once done, the headers of every request.
+    fn serve(responses: Vec<&'static str>) -> (Url, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/api-1.0.0.tar.gz", listener.local_addr().unwrap())).unwrap();
+        let server = std::thread::spawn(move || {
+            responses.into_iter().map(|response| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                while reader.read_line(&mut request).unwrap() > 2 {}
+                stream.write_all(response.as_bytes()).unwrap();
+                request.to_lowercase()
+            }).collect()
+        });
+        (url, server)
+    }
+
+    fn entry(data_dir: &Path, url: &str, content: &[u8], last_used: u64) -> CacheEntry {
+        let digest = bytes_digest(content);
+        let blobs_dir = downloads_dir(data_dir).join(BLOBS_DIR);
+        fs::create_dir_all(&blobs_dir).unwrap();
+        fs::write(blobs_dir.join(hex_of(&digest)), content).unwrap();
+        let entry = CacheEntry { url: Url::parse(url).unwrap(), digest, etag: None, last_modified: None, size: content.len() as u64, last_used };
+        write_entry(&url_entry_path(data_dir, &entry.url), &entry).unwrap();
+        entry
+    }
+
+    fn set_modified(path: &Path, seconds_ago: u64) {
+        fs::File::open(path).unwrap().set_modified(SystemTime::now() - Duration::from_secs(seconds_ago)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn revalidates_cached_urls() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 2\r\nConnection: close\r\n\r\nv1",
+            "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nETag: \"v2\"\r\nContent-Length: 2\r\nConnection: close\r\n\r\nv2",
+        ]);
+
+        assert_eq!(read_cached(&policy(dir.path(), false), &url).await.unwrap(), b"v1");
+        assert_eq!(read_cached(&policy(dir.path(), false), &url).await.unwrap(), b"v1");
+        assert_eq!(read_cached(&policy(dir.path(), false), &url).await.unwrap(), b"v2");
+
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""), "{}", requests[1]);
+        let entries = cache_entries(dir.path()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].etag.as_deref(), entries[0].digest.clone()), (Some("\"v2\""), bytes_digest(b"v2")));
+    }
+
+    #[tokio::test]
+    async fn offline_only_uses_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cached = entry(dir.path(), "https://example.com/api-1.0.0.tar.gz", b"cached", 0);
+
+        assert_eq!(read_cached(&policy(dir.path(), true), &cached.url).await.unwrap(), b"cached");
+        assert!(cache_entries(dir.path()).unwrap()[0].last_used > 0);
+        let missing = Url::parse("https://example.com/api-2.0.0.tar.gz").unwrap();
+        let error = read_cached(&policy(dir.path(), true), &missing).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert!(error.to_string().contains("--offline"), "{}", error);
+
+        let source = GitSource::parse("git+https://example.com/platform.git?ref=main").unwrap();
+        let error = checkout_cached(&policy(dir.path(), true), &source, dir.path()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn lists_the_most_recently_used_first() {
+        let dir = tempfile::tempdir().unwrap();
+        entry(dir.path(), "https://example.com/a.tar.gz", b"a", 10);
+        entry(dir.path(), "https://example.com/b.tar.gz", b"b", 30);
+        entry(dir.path(), "https://example.com/c.tar.gz", b"c", 20);
+
+        let urls: Vec<String> = cache_entries(dir.path()).unwrap().iter().map(|entry| entry.url.to_string()).collect();
+        assert_eq!(urls, ["https://example.com/b.tar.gz", "https://example.com/c.tar.gz", "https://example.com/a.tar.gz"]);
+    }
+
+    #[test]
+    fn prunes_what_was_not_used_recently() {
+        let dir = tempfile::tempdir().unwrap();
+        let day = 24 * 60 * 60;
+        let recent = entry(dir.path(), "https://example.com/recent.tar.gz", b"recent", now());
+        let stale = entry(dir.path(), "https://example.com/stale.tar.gz", b"stale", now() - 2 * day);
+        let blobs_dir = downloads_dir(dir.path()).join(BLOBS_DIR);
+        for digest in [&recent.digest, &stale.digest] {
+            set_modified(&blobs_dir.join(hex_of(digest)), 2 * day);
+        }
+        let git_dir = downloads_dir(dir.path()).join(GIT_DIR);
+        for (checkout, age) in [("old", 2 * day), ("new", 0)] {
+            fs::create_dir_all(git_dir.join(checkout)).unwrap();
+            set_modified(&git_dir.join(checkout), age);
+        }
+
+        let freed = prune(dir.path(), Duration::from_secs(day)).unwrap();
+
+        assert!(freed >= b"stale".len() as u64);
+        let urls: Vec<Url> = cache_entries(dir.path()).unwrap().into_iter().map(|entry| entry.url).collect();
+        assert_eq!(urls, [recent.url]);
+        assert!(blobs_dir.join(hex_of(&recent.digest)).is_file());
+        assert!(!blobs_dir.join(hex_of(&stale.digest)).exists());
+        assert!(!git_dir.join("old").exists());
+        assert!(git_dir.join("new").exists());
+    }
+
+    #[test]
+    fn clear_keeps_the_repository_indexes() {
+        let dir = tempfile::tempdir().unwrap();
+        entry(dir.path(), "https://example.com/a.tar.gz", b"a", 0);
+        fs::write(dir.path().join("cache").join("main-index.yaml"), "apiVersion: v1\n").unwrap();
+
+        assert!(clear(dir.path()).unwrap() > 0);
+        assert!(!downloads_dir(dir.path()).exists());
+        assert!(dir.path().join("cache").join("main-index.yaml").is_file());
+        assert_eq!(clear(dir.path()).unwrap(), 0);
+    }
+}
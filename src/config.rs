@@ -0,0 +1,63 @@
+use std::{fs, io};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::signing::Keyring;
+
+/// File in the local data directory with the settings of the user.
+pub const CONFIG_FILE: &str = "config.yaml";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct UserConfig {
+    /// refuse packaged generators from remote sources unless they are signed by a key of the keyring
+    #[serde(rename = "require_signatures")]
+    pub require_signatures: bool,
+
+    /// hosts remote generators are used from without asking, e.g. `github.com` or `*.example.com`
+    #[serde(rename = "allowed_hosts")]
+    pub allowed_hosts: Vec<String>,
+
+    /// hosts remote generators are never used from, taking precedence over `allowed_hosts`
+    #[serde(rename = "denied_hosts")]
+    pub denied_hosts: Vec<String>,
+}
+
+pub fn read_user_config(data_dir: &Path) -> Result<UserConfig, io::Error> {
+    let path = data_dir.join(CONFIG_FILE);
+    if !path.is_file() {
+        return Ok(UserConfig::default());
+    }
+    serde_yaml::from_str(&fs::read_to_string(&path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Cannot deserialize file {:?} due to error:{:?}", path, e)))
+}
+
+/// How generators from remote sources are checked before they are used.
+#[derive(Debug, Clone)]
+pub struct FetchPolicy {
+    /// whether every generator fetched from a remote source must be a package with a valid signature
+    pub verify: bool,
+    /// the keys signatures are verified against
+    pub keyring: Keyring,
+    /// hosts that are trusted without asking
+    pub allowed_hosts: Vec<String>,
+    /// hosts that are never trusted
+    pub denied_hosts: Vec<String>,
+    /// the local data directory the trust decisions of the user are recorded in and downloads are cached in
+    pub data_dir: PathBuf,
+    /// whether only the download cache is used, failing instead of fetching
+    pub offline: bool,
+}
+
+impl FetchPolicy {
+    /// Signatures are verified when asked for with `--verify` or when the user config requires them.
+    pub fn new(data_dir: &Path, config: &UserConfig, verify: bool, keyring: Keyring) -> Self {
+        FetchPolicy {
+            verify: verify || config.require_signatures,
+            keyring,
+            allowed_hosts: config.allowed_hosts.clone(),
+            denied_hosts: config.denied_hosts.clone(),
+            data_dir: data_dir.to_path_buf(),
+            offline: false,
+        }
+    }
+}
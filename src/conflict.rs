@@ -0,0 +1,405 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::{fs, io};
+use std::io::{ErrorKind, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use clap::ValueEnum;
+use glob::Pattern;
+use rrgen::{ConsolePrinter, FsDriver, Printer, RRgen, RealFsDriver};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use crate::error::ProtypoError;
+use crate::plan::{Change, Plan, PlannedFile};
+
+/// What happens to an output file that already exists with other content than the generated one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictStrategy {
+    /// the generated file replaces the existing one
+    #[default]
+    Overwrite,
+    /// the existing file is kept as it is
+    Skip,
+    /// the user is shown a diff and asked for each file
+    Ask,
+    /// the existing file is moved to `<file>.bak` before the generated one is written
+    Backup,
+    /// the generation stops before writing anything
+    Fail,
+}
+
+/// A rule of the `conflicts` section of a `Generator.yaml`, for the output files of the generator matching `path`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConflictRule {
+    /// glob relative to the output directory, e.g. `src/**/*.rs`
+    #[serde(rename = "path")]
+    pub path: String,
+
+    #[serde(rename = "strategy")]
+    pub strategy: ConflictStrategy,
+}
+
+/// How the conflicts of a generation are resolved. The `on_conflict` frontmatter key of a template takes precedence
+/// over the first matching rule of the generator, which takes precedence over `default`.
+#[derive(Default)]
+pub struct ConflictPolicy {
+    pub default: ConflictStrategy,
+    /// directory the paths of the rules are relative to
+    pub output_dir: PathBuf,
+    /// the compiled rules of each generator, by key
+    pub rules: HashMap<String, Vec<(Pattern, ConflictStrategy)>>,
+}
+
+impl ConflictPolicy {
+    pub fn strategy(&self, file: &PlannedFile) -> ConflictStrategy {
+        if let Some(strategy) = file.on_conflict {
+            return strategy;
+        }
+        let relative = file.path.strip_prefix(&self.output_dir).unwrap_or(&file.path);
+        self.rules.get(&file.generator).into_iter().flatten()
+            .find(|(pattern, _)| pattern.matches_path(relative))
+            .map(|(_, strategy)| *strategy)
+            .unwrap_or(self.default)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resolution {
+    Write,
+    Skip,
+    Backup,
+}
+
+/// What happens to the existing files a generation changes, decided before anything is written.
+#[derive(Debug, Default)]
+pub struct Resolutions {
+    /// files to keep as they are, never written by the generation
+    skipped: HashSet<PathBuf>,
+    /// files to move to a backup before the generation, by the generator writing them
+    backed_up: Vec<(String, PathBuf)>,
+}
+
+/// Decides what happens to every existing file the plan would replace with other content, as the conflict policy
+/// the plan was made with says. All conflicts are resolved before the generation, so nothing is written when one
+/// of them fails it or the user quits. Files that are only injected into are not conflicts, the injection keeps their content.
+pub fn resolve_conflicts(plan: &Plan) -> Result<Resolutions, ProtypoError> {
+    let mut resolutions = Resolutions::default();
+    let mut failed = Vec::new();
+    let changes = plan.changes();
+    for file in &plan.files {
+        let Some(strategy) = file.conflict else {
+            continue;
+        };
+        let resolution = match strategy {
+            ConflictStrategy::Overwrite => Resolution::Write,
+            ConflictStrategy::Skip => Resolution::Skip,
+            ConflictStrategy::Backup => Resolution::Backup,
+            ConflictStrategy::Ask => match changes.iter().find(|change| change.path == file.path) {
+                Some(change) => ask(change).map_err(|e| ProtypoError::write(&file.generator, &file.path, e))?,
+                // later injections gave the file its current content again
+                None => Resolution::Write,
+            },
+            ConflictStrategy::Fail => {
+                failed.push((file.generator.clone(), file.path.clone()));
+                continue;
+            }
+        };
+        match resolution {
+            Resolution::Write => debug!("{} - Overwriting {}", file.generator, file.path.display()),
+            Resolution::Skip => {
+                resolutions.skipped.insert(file.path.clone());
+            }
+            Resolution::Backup => resolutions.backed_up.push((file.generator.clone(), file.path.clone())),
+        }
+    }
+
+    if let Some((generator, path)) = failed.first() {
+        for (_, path) in &failed {
+            eprintln!("{} already exists with other content", path.display());
+        }
+        return Err(ProtypoError::write(generator, path, io::Error::new(ErrorKind::AlreadyExists,
+            format!("{} existing file(s) would change, nothing was written. Choose what happens to them with --on-conflict", failed.len()))));
+    }
+    Ok(resolutions)
+}
+
+impl Resolutions {
+    /// Moves the files to back up out of the way of the generation.
+    pub fn back_up(&self) -> Result<(), ProtypoError> {
+        for (generator, path) in &self.backed_up {
+            let backup = backup_path(path);
+            fs::rename(path, &backup).map_err(|e| ProtypoError::write(generator, &backup, e))?;
+            println!("Moved {} to {}", path.display(), backup.display());
+        }
+        Ok(())
+    }
+
+    /// Whether the existing file at `path` is kept, in which case the generation must not write it.
+    pub fn keeps(&self, path: &Path) -> bool {
+        let keeps = self.skipped.contains(path);
+        if keeps {
+            report_kept(path);
+        }
+        keeps
+    }
+
+    /// Makes `rrgen` leave the kept files alone, it writes everything else as it would.
+    pub fn keep_skipped(&self, rrgen: RRgen) -> RRgen {
+        let keeping = Rc::new(RefCell::new(Keeping { skipped: self.skipped.clone(), pending: None }));
+        rrgen.with_fs(Box::new(KeepingFs(keeping.clone())))
+            .with_printer(Box::new(KeepingPrinter(keeping)))
+    }
+}
+
+/// What the filesystem and printer of a generation keeping the skipped files share.
+struct Keeping {
+    skipped: HashSet<PathBuf>,
+    /// the kept file rrgen reported overwriting, whose content it writes next
+    pending: Option<PathBuf>,
+}
+
+/// Writes like rrgen does, except the content of a kept file. Injections into it still apply, as they do in the plan.
+struct KeepingFs(Rc<RefCell<Keeping>>);
+
+impl FsDriver for KeepingFs {
+    fn write_file(&self, path: &Path, content: &str) -> rrgen::Result<()> {
+        if self.0.borrow_mut().pending.take().is_some_and(|pending| pending == path) {
+            return Ok(());
+        }
+        RealFsDriver {}.write_file(path, content)
+    }
+
+    fn read_file(&self, path: &Path) -> rrgen::Result<String> {
+        RealFsDriver {}.read_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        RealFsDriver {}.exists(path)
+    }
+}
+
+/// Prints like rrgen does, and reports kept files as skipped instead of overwritten.
+struct KeepingPrinter(Rc<RefCell<Keeping>>);
+
+impl KeepingPrinter {
+    fn keeps(&self, path: &Path) -> bool {
+        let mut keeping = self.0.borrow_mut();
+        if !keeping.skipped.contains(path) {
+            return false;
+        }
+        report_kept(path);
+        keeping.pending = Some(path.to_path_buf());
+        true
+    }
+}
+
+impl Printer for KeepingPrinter {
+    fn overwrite_file(&self, file_to: &Path) {
+        if !self.keeps(file_to) {
+            ConsolePrinter {}.overwrite_file(file_to);
+        }
+    }
+
+    fn skip_exists(&self, file_to: &Path) {
+        ConsolePrinter {}.skip_exists(file_to);
+    }
+
+    fn add_file(&self, file_to: &Path) {
+        if !self.keeps(file_to) {
+            ConsolePrinter {}.add_file(file_to);
+        }
+    }
+
+    fn injected(&self, file_to: &Path) {
+        ConsolePrinter {}.injected(file_to);
+    }
+}
+
+fn report_kept(path: &Path) {
+    println!("Skipped {}, it has other content than generated", path.display());
+}
+
+/// Shows the diff of a conflicting file and asks the user what to do with it.
+fn ask(change: &Change) -> Result<Resolution, io::Error> {
+    if !io::stdin().is_terminal() {
+        return Err(io::Error::new(ErrorKind::Unsupported,
+            "it already exists with other content and there is no terminal to ask on, choose another --on-conflict strategy"));
+    }
+    print!("{}", change.unified_diff(io::stdout().is_terminal()));
+    loop {
+        eprint!("{} already exists with other content. [o]verwrite, [s]kip, [b]ackup or [q]uit? ", change.path.display());
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer)? == 0 {
+            answer = "q".to_string();
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "o" | "overwrite" => return Ok(Resolution::Write),
+            "s" | "skip" => return Ok(Resolution::Skip),
+            "b" | "backup" => return Ok(Resolution::Backup),
+            "q" | "quit" => return Err(io::Error::new(ErrorKind::Interrupted, "generation stopped, nothing was written")),
+            _ => {}
+        }
+    }
+}
+
+/// `<file>.bak`, or `<file>.bak.<n>` with the first free `n` when earlier backups exist.
+fn backup_path(path: &Path) -> PathBuf {
+    let with_suffix = |suffix: String| {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(suffix);
+        PathBuf::from(backup)
+    };
+    let mut backup = with_suffix(".bak".to_string());
+    let mut n = 1;
+    while backup.exists() {
+        backup = with_suffix(format!(".bak.{}", n));
+        n += 1;
+    }
+    backup
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::TempDir;
+    use crate::generator::TemplateRenderer;
+    use crate::plan::{Action, Planner};
+
+    const GENERATOR: &str = "app:1.0.0";
+
+    fn policy(dir: &TempDir, default: ConflictStrategy, rules: &[(&str, ConflictStrategy)]) -> ConflictPolicy {
+        let rules = rules.iter().map(|(path, strategy)| (Pattern::new(path).unwrap(), *strategy)).collect();
+        ConflictPolicy {
+            default,
+            output_dir: dir.path().to_path_buf(),
+            rules: HashMap::from([(GENERATOR.to_string(), rules)]),
+        }
+    }
+
+    /// Renders each template, given as its frontmatter and body, with `renderer`.
+    fn render(renderer: &mut dyn TemplateRenderer, templates: &[(String, &str)]) {
+        for (frontmatter, body) in templates {
+            let input = format!("{}\n===\n{}", frontmatter, body);
+            renderer.render(GENERATOR, Path::new("t"), &input, &json!({})).unwrap();
+        }
+    }
+
+    fn plan(policy: ConflictPolicy, templates: &[(String, &str)]) -> Plan {
+        let mut planner = Planner::new("---\n", "===\n", policy);
+        render(&mut planner, templates);
+        planner.into_plan()
+    }
+
+    fn template(dir: &TempDir, name: &str) -> String {
+        format!("to: {}", dir.path().join(name).display())
+    }
+
+    fn writer() -> RRgen {
+        let mut rrgen = RRgen::default();
+        rrgen.document_separator = "---\n".to_string();
+        rrgen.frontmatter_separator = "===\n".to_string();
+        rrgen
+    }
+
+    #[test]
+    fn backs_up_to_the_first_free_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        assert_eq!(backup_path(&file), dir.path().join("a.txt.bak"));
+        fs::write(dir.path().join("a.txt.bak"), "").unwrap();
+        assert_eq!(backup_path(&file), dir.path().join("a.txt.bak.1"));
+        fs::write(dir.path().join("a.txt.bak.1"), "").unwrap();
+        assert_eq!(backup_path(&file), dir.path().join("a.txt.bak.2"));
+    }
+
+    #[test]
+    fn only_existing_files_with_other_content_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("same.txt"), "same\n").unwrap();
+        fs::write(dir.path().join("mod.rs"), "mod a;\n").unwrap();
+        let injection = format!("{}\ninjections:\n- into: {}\n  append: true\n  content: \"mod b;\"",
+            template(&dir, "b.rs"), dir.path().join("mod.rs").display());
+        let plan = plan(policy(&dir, ConflictStrategy::Fail, &[]), &[
+            (template(&dir, "new.txt"), "new\n"),
+            (template(&dir, "same.txt"), "same\n"),
+            (injection, "// b\n"),
+        ]);
+
+        assert!(plan.files.iter().all(|file| file.conflict.is_none()), "{:?}", plan.files);
+        assert!(resolve_conflicts(&plan).is_ok());
+    }
+
+    #[test]
+    fn fails_before_writing_anything() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "edited\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "edited\n").unwrap();
+        let plan = plan(policy(&dir, ConflictStrategy::Fail, &[]), &[
+            (template(&dir, "a.txt"), "generated\n"),
+            (template(&dir, "b.txt"), "generated\n"),
+        ]);
+
+        let error = resolve_conflicts(&plan).unwrap_err();
+        assert_eq!(error.exit_code(), 8);
+        assert!(error.to_string().contains("2 existing file(s) would change"), "{}", error);
+    }
+
+    #[test]
+    fn frontmatter_takes_precedence_over_rules_and_rules_over_the_default() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.rs", "b.rs", "c.txt"] {
+            fs::write(dir.path().join(name), "edited\n").unwrap();
+        }
+        let plan = plan(policy(&dir, ConflictStrategy::Overwrite, &[("*.rs", ConflictStrategy::Backup)]), &[
+            (format!("{}\non_conflict: skip", template(&dir, "a.rs")), "generated\n"),
+            (template(&dir, "b.rs"), "generated\n"),
+            (template(&dir, "c.txt"), "generated\n"),
+        ]);
+
+        let conflicts: Vec<Option<ConflictStrategy>> = plan.files.iter().map(|file| file.conflict).collect();
+        assert_eq!(conflicts, [Some(ConflictStrategy::Skip), Some(ConflictStrategy::Backup), Some(ConflictStrategy::Overwrite)]);
+    }
+
+    #[test]
+    fn skipped_files_are_planned_as_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "edited\n").unwrap();
+        let plan = plan(policy(&dir, ConflictStrategy::Skip, &[]), &[(template(&dir, "a.txt"), "generated\n")]);
+
+        assert_eq!(plan.files[0].action, Action::Skip);
+        assert!(plan.changes().is_empty());
+    }
+
+    #[test]
+    fn skipped_files_are_never_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let kept = dir.path().join("a.txt");
+        fs::write(&kept, "edited\n").unwrap();
+        let modified = fs::metadata(&kept).unwrap().modified().unwrap();
+        let templates = [(template(&dir, "a.txt"), "generated\n"), (template(&dir, "b.txt"), "generated\n")];
+        let resolutions = resolve_conflicts(&plan(policy(&dir, ConflictStrategy::Skip, &[]), &templates)).unwrap();
+
+        render(&mut resolutions.keep_skipped(writer()), &templates);
+        assert_eq!(fs::read_to_string(&kept).unwrap(), "edited\n");
+        assert_eq!(fs::metadata(&kept).unwrap().modified().unwrap(), modified);
+        assert_eq!(fs::read_to_string(dir.path().join("b.txt")).unwrap(), "generated\n");
+    }
+
+    #[test]
+    fn backed_up_files_are_moved_before_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        fs::write(&file, "edited\n").unwrap();
+        let templates = [(template(&dir, "a.txt"), "generated\n")];
+        let resolutions = resolve_conflicts(&plan(policy(&dir, ConflictStrategy::Backup, &[]), &templates)).unwrap();
+
+        resolutions.back_up().unwrap();
+        assert!(!file.exists());
+        render(&mut resolutions.keep_skipped(writer()), &templates);
+        assert_eq!(fs::read_to_string(dir.path().join("a.txt.bak")).unwrap(), "edited\n");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "generated\n");
+    }
+}
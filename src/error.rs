@@ -0,0 +1,70 @@
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors of loading and running a generator, each carrying the generator it happened in.
+/// `generator` is the key (`name:version`) of the generator, or its directory when the key is not known yet.
+#[derive(Debug, Error)]
+pub enum ProtypoError {
+    /// a generator or one of its dependencies cannot be found or read
+    #[error("{generator} - cannot load {path}: {message}")]
+    Load { generator: String, path: PathBuf, message: String },
+
+    /// a remote generator cannot be downloaded or does not match its expected digest
+    #[error("{generator} - cannot fetch {url}: {message}")]
+    Fetch { generator: String, url: String, message: String },
+
+    /// a file of a generator is not valid YAML or JSON, or has an unexpected shape
+    #[error("{generator} - cannot parse {path}: {message}")]
+    Parse { generator: String, path: PathBuf, message: String },
+
+    /// the values do not match the `values.schema.json` of a generator
+    #[error("{generator} - invalid values for {path}: {message}")]
+    Schema { generator: String, path: PathBuf, message: String },
+
+    /// a template cannot be rendered
+    #[error("{generator} - cannot render {path}: {message}")]
+    Render { generator: String, path: PathBuf, message: String },
+
+    /// an output file cannot be written
+    #[error("{generator} - cannot write {path}: {source}")]
+    Write { generator: String, path: PathBuf, source: io::Error },
+}
+
+impl ProtypoError {
+    /// Exit code of the process when it fails with this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ProtypoError::Load { .. } => 3,
+            ProtypoError::Fetch { .. } => 4,
+            ProtypoError::Parse { .. } => 5,
+            ProtypoError::Schema { .. } => 6,
+            ProtypoError::Render { .. } => 7,
+            ProtypoError::Write { .. } => 8,
+        }
+    }
+
+    pub fn load(generator: impl Into<String>, path: impl Into<PathBuf>, message: impl ToString) -> Self {
+        ProtypoError::Load { generator: generator.into(), path: path.into(), message: message.to_string() }
+    }
+
+    pub fn fetch(generator: impl Into<String>, url: impl ToString, message: impl ToString) -> Self {
+        ProtypoError::Fetch { generator: generator.into(), url: url.to_string(), message: message.to_string() }
+    }
+
+    pub fn parse(generator: impl Into<String>, path: impl Into<PathBuf>, message: impl ToString) -> Self {
+        ProtypoError::Parse { generator: generator.into(), path: path.into(), message: message.to_string() }
+    }
+
+    pub fn schema(generator: impl Into<String>, path: impl Into<PathBuf>, message: impl ToString) -> Self {
+        ProtypoError::Schema { generator: generator.into(), path: path.into(), message: message.to_string() }
+    }
+
+    pub fn render(generator: impl Into<String>, path: impl Into<PathBuf>, message: impl ToString) -> Self {
+        ProtypoError::Render { generator: generator.into(), path: path.into(), message: message.to_string() }
+    }
+
+    pub fn write(generator: impl Into<String>, path: impl Into<PathBuf>, source: io::Error) -> Self {
+        ProtypoError::Write { generator: generator.into(), path: path.into(), source }
+    }
+}
@@ -1,25 +1,32 @@
 use crate::{Context, Url};
+use crate::cache::{checkout_cached, extract_cached, read_cached};
+use crate::config::FetchPolicy;
+use crate::conflict::{ConflictRule, ConflictStrategy, Resolutions};
+use crate::error::ProtypoError;
+use crate::git::{is_git_uri, GitSource};
+use crate::plan::Planner;
+use crate::lock::{directory_digest, read_lock, LockedDependency, LOCK_FILE};
+use crate::remote::fetch_from_repository;
+use crate::signing::{check_signature, check_unsigned_source};
+use crate::trust::{check_source, confirm_source};
+use crate::repository::{local_data_dir, local_generators_dir, parse_version_req, resolve_installed, write_install_metadata, InstallMetadata, INSTALL_METADATA};
+use crate::values::{fill_defaults, merge_values, nest_at_path, schema_defaults, validate_values, value_at_path, SchemaViolation};
 use std::{fs, path::{Path, PathBuf}, io};
-use std::collections::HashMap;
-use std::io::{BufReader, Cursor, ErrorKind};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::ErrorKind;
 use anyhow::anyhow;
 use clap::builder::Str;
-use flate2::bufread::GzDecoder;
+use futures::future::BoxFuture;
 use futures::stream;
-use git2::Repository;
 use glob::glob;
 use reqwest::{get, Client, Response};
 use rrgen::{GenResult, RRgen};
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Map, Value};
-use tar::Archive;
-use tempfile::tempdir;
-use tokio::fs::{copy, create_dir_all, File};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tracing::{debug, error, info};
+use tracing::{debug, info};
 use tracing::field::debug;
 use tracing_subscriber::Layer;
-use zip::ZipArchive;
 use crate::path_to_json;
 use serde::de::DeserializeOwned;
 use tracing_subscriber::fmt::format;
@@ -27,7 +34,7 @@ use tokio_stream::wrappers::ReadDirStream;
 use json_value_merge::Merge;
 use tokio_stream::StreamExt;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct Generator {
     pub base_path: String,
     pub generator_yaml: GeneratorYaml,
@@ -39,9 +46,12 @@ pub(crate) struct Generator {
     pub entities: Value,
     pub templates: Option<Vec<String>>,
     pub dependencies: Option<Vec<Generator>>,
+    /// the declaration in the parent's `Generator.yaml` that loaded this generator
+    #[serde(default)]
+    pub dependency: Option<Dependency>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GeneratorYaml {
     #[serde(rename = "apiVersion")]
     pub api_version: String,
@@ -52,50 +62,58 @@ pub struct GeneratorYaml {
     #[serde(rename = "version")]
     pub version: String,
 
-    #[serde(rename = "description")]
+    #[serde(rename = "description", skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
 
-    #[serde(rename = "keywords")]
+    #[serde(rename = "keywords", skip_serializing_if = "Option::is_none")]
     pub keywords: Option<Vec<String>>,
 
-    #[serde(rename = "home")]
+    #[serde(rename = "home", skip_serializing_if = "Option::is_none")]
     pub home: Option<String>,
 
-    #[serde(rename = "sources")]
+    #[serde(rename = "sources", skip_serializing_if = "Option::is_none")]
     pub sources: Option<Vec<String>>,
 
-    #[serde(rename = "dependencies")]
+    #[serde(rename = "dependencies", skip_serializing_if = "Option::is_none")]
     pub dependencies: Option<Vec<Dependency>>,
 
-    #[serde(rename = "maintainers")]
+    #[serde(rename = "maintainers", skip_serializing_if = "Option::is_none")]
     pub maintainers: Option<Vec<Maintainer>>,
 
-    #[serde(rename = "icon")]
+    #[serde(rename = "icon", skip_serializing_if = "Option::is_none")]
     pub icon: Option<String>,
 
-    #[serde(rename = "deprecated")]
+    #[serde(rename = "deprecated", skip_serializing_if = "Option::is_none")]
     pub deprecated: Option<bool>,
 
-    #[serde(rename = "annotations")]
+    #[serde(rename = "annotations", skip_serializing_if = "Option::is_none")]
     pub annotations: Option<Annotations>,
+
+    /// what happens to existing output files of this generator, the first rule whose path matches decides
+    #[serde(rename = "conflicts", skip_serializing_if = "Option::is_none")]
+    pub conflicts: Option<Vec<ConflictRule>>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Annotations {
     #[serde(rename = "example")]
     pub example: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Dependency {
     #[serde(rename = "name")]
     pub name: String,
 
+    /// semantic version constraint, e.g. `1.2.3`, `^1.2`, `~0.3` or `>=1, <2`
     #[serde(rename = "version")]
     pub version: String,
 
+    /// where to load the dependency from, when missing it is resolved from the local repository by name and version.
+    /// Either a `file://` directory, an `http(s)://` archive, a `git+` source, see [`GitSource`],
+    /// or `repo://<repository>/<name>` for a generator of a repository added with `protypo repo add`
     #[serde(rename = "url")]
-    pub repository: Url,
+    pub repository: Option<Url>,
 
     #[serde(rename = "condition")]
     pub condition: Option<String>,
@@ -104,13 +122,27 @@ pub struct Dependency {
     pub tags: Option<Vec<String>>,
 
     #[serde(rename = "import-values")]
-    pub import_values: Option<Vec<String>>,
+    pub import_values: Option<Vec<ImportValue>>,
 
     #[serde(rename = "alias")]
     pub alias: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A value the parent imports from a dependency, as in helm either the name of an entry
+/// of the `exports` section of the dependency or an explicit pair of dot separated paths.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ImportValue {
+    Exported(String),
+    Mapped {
+        #[serde(rename = "child")]
+        child: String,
+        #[serde(rename = "parent")]
+        parent: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Maintainer {
     #[serde(rename = "name")]
     pub name: String,
@@ -122,92 +154,231 @@ pub struct Maintainer {
     pub url: Option<String>,
 }
 
-pub async fn install_template(uri: &String, destination: &PathBuf) {
-    let source = uri;
+/// Installs the generator found at `uri` into the local repository `destination` as `<name>/<version>`,
+/// returning its `Generator.yaml` and the directory it was installed into.
+/// An installed version is only replaced when `force` is set, and packages are verified as the policy asks.
+pub async fn install_template(uri: &str, destination: &Path, force: bool, policy: &FetchPolicy) -> Result<(GeneratorYaml, PathBuf), ProtypoError> {
     info!("Starting the install process...");
-    debug!("Source: {}, Destination: {}", source, destination.display());
-    let generator_dir = prepare_generator_source(uri).await.unwrap();
+    debug!("Source: {}, Destination: {}", uri, destination.display());
+    let (generator_dir, commit) = fetch_template(uri, policy).await?;
     debug!("generator_dir:{}", generator_dir.display());
-    move_to_repo_root(generator_dir, destination).await.unwrap();
+    let metadata = InstallMetadata { source: uri.to_string(), commit };
+    move_to_repo_root(&generator_dir, destination, force, &metadata)
+}
+
+/// Installs the generator fetched from `uri` into `generator_dir` like [`install_template`], or uses the installed copy
+/// of the same version when it has the same content. An installed copy whose content differs is an error,
+/// it is only replaced by an explicit `protypo install --force`.
+pub fn install_or_reuse(uri: &str, generator_dir: &Path, commit: Option<String>, destination: &Path) -> Result<PathBuf, ProtypoError> {
+    let metadata = InstallMetadata { source: uri.to_string(), commit };
+    match move_to_repo_root(generator_dir, destination, false, &metadata) {
+        Ok((_, path)) => Ok(path),
+        Err(ProtypoError::Write { generator, path, source }) if source.kind() == ErrorKind::AlreadyExists => {
+            let fetched = directory_digest(generator_dir).map_err(|e| ProtypoError::load(&generator, generator_dir, e))?;
+            let installed = directory_digest(&path).map_err(|e| ProtypoError::load(&generator, &path, e))?;
+            if fetched != installed {
+                return Err(ProtypoError::write(&generator, &path, io::Error::new(ErrorKind::AlreadyExists,
+                    format!("the installed generator differs from {} ({} instead of {}), use `protypo install --force {}` to replace it",
+                        uri, installed, fetched, uri))));
+            }
+            info!("Using installed generator in {}", path.display());
+            Ok(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Fetches the generator found at `uri` without installing it, checked against the trust policy.
+/// Returns its directory and, for git sources, the commit that was checked out.
+pub async fn fetch_template(uri: &str, policy: &FetchPolicy) -> Result<(PathBuf, Option<String>), ProtypoError> {
+    let needs_consent = check_source(policy, uri).map_err(|e| ProtypoError::fetch(uri, uri, e))?;
+    let (generator_dir, commit) = prepare_generator_source(uri, policy).await?;
+    if needs_consent {
+        confirm_source(policy, uri, &generator_dir).map_err(|e| ProtypoError::fetch(uri, uri, e))?;
+    }
+    Ok((generator_dir, commit))
+}
+
+/// Where the templates of a generator are rendered to: written by rrgen, or collected into a plan by rrgen
+/// rendering into memory, see [`crate::plan::Planner`].
+pub(crate) trait TemplateRenderer {
+    /// Makes the templates in `dir` available to the templates rendered afterwards, e.g. for includes.
+    fn add_template_dir(&mut self, dir: &Path) -> Result<(), String>;
+    /// Renders a template of the generator `generator` with `context`.
+    fn render(&mut self, generator: &str, template: &Path, input: &str, context: &Value) -> Result<(), String>;
+}
+
+impl TemplateRenderer for RRgen {
+    fn add_template_dir(&mut self, dir: &Path) -> Result<(), String> {
+        self.add_dir_to_tera(dir);
+        Ok(())
+    }
+
+    fn render(&mut self, _generator: &str, _template: &Path, input: &str, context: &Value) -> Result<(), String> {
+        self.generate(input, context).map(|_| ()).map_err(|e| error_chain(&e))
+    }
+}
+
+/// Tera errors keep the cause, e.g. the missing variable, in their source.
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+/// Directory of a generator where its dependencies are vendored by `protypo dependency build`.
+pub const VENDOR_DIR: &str = "generators";
+
+/// Where the versions of the dependencies of a generator come from.
+#[derive(Clone, Copy)]
+enum Pinning<'a> {
+    /// the `Generator.lock` of the generator, if it has one
+    Lockfile,
+    /// the entries of the lockfile of an ancestor
+    Locked(&'a [LockedDependency]),
+    /// the dependency declarations, ignoring any lockfile
+    Unlocked,
 }
 
 impl Generator {
 
-    fn key(&self) -> String {
+    pub fn key(&self) -> String {
         format!("{}:{}", self.generator_yaml.name, self.generator_yaml.version)
     }
 
-    pub async fn from_url(url: &Url, base_path: &Path) -> Result<Self, io::Error> {
-        let url_path = if url.scheme() == "file" {
-            let url = url.to_string();
-            debug!("url: {}", url);
-            let file_path = url.strip_prefix("file://").unwrap().to_string();
-            debug!("Using url is filesystem path: {}", file_path);
-            let path = Path::new(&file_path);
-            if path.is_relative() {
-                let path = base_path.join(path);
-                path
-            } else {
-                path.to_path_buf()
+    /// Key under which the values of this generator are overridden, its alias or else its name.
+    pub fn namespace(&self) -> &str {
+        self.dependency.as_ref()
+            .and_then(|dependency| dependency.alias.as_deref())
+            .unwrap_or(self.generator_yaml.name.as_str())
+    }
+
+    /// Identifies the generator together with the values it is used with, so that a generator used twice
+    /// with the same values is only processed once but a second alias with other values is not dropped.
+    fn instance_key(&self, values: &Value) -> (String, String) {
+        (self.key(), values.to_string())
+    }
+
+    /// Loads the generator in `base_path` and its dependencies, pinned by its `Generator.lock` when present.
+    /// Remote dependencies are verified as the policy asks.
+    pub async fn from_directory(base_path: &Path, policy: &FetchPolicy) -> Result<Self, ProtypoError> {
+        Loader::new(policy).load(base_path.to_path_buf(), Pinning::Lockfile).await
+    }
+
+    /// Loads the generator in `base_path` resolving its dependency tree again, ignoring any `Generator.lock`.
+    pub async fn from_directory_unlocked(base_path: &Path, policy: &FetchPolicy) -> Result<Self, ProtypoError> {
+        Loader::new(policy).load(base_path.to_path_buf(), Pinning::Unlocked).await
+    }
+
+    /// Copies the loaded dependencies into `<destination>/generators/<name>/<version>`, each with its own
+    /// dependencies vendored inside it, so that the generator can be loaded without network access.
+    pub fn vendor_dependencies(&self, destination: &Path) -> Result<(), ProtypoError> {
+        for dependency in self.dependencies.iter().flatten() {
+            let vendored_path = destination.join(VENDOR_DIR)
+                .join(&dependency.generator_yaml.name)
+                .join(&dependency.generator_yaml.version);
+            if Path::new(&dependency.base_path) != vendored_path {
+                if vendored_path.exists() {
+                    fs::remove_dir_all(&vendored_path)
+                        .map_err(|e| ProtypoError::write(dependency.key(), &vendored_path, e))?;
+                }
+                info!("{} - Vendoring dependency {} into {}", self.key(), dependency.key(), vendored_path.display());
+                copy_local_path(Path::new(&dependency.base_path), &vendored_path)
+                    .map_err(|e| ProtypoError::write(dependency.key(), &vendored_path, e))?;
             }
-        } else if url.scheme() == "http" || url.scheme() == "https" {
-            // For http:// or https:// URLs, handle download and return a path to the downloaded file
-            let temp_path = download_and_extract_to_temp(url.clone()).await?;
-            temp_path
-        } else {
-            // Unsupported scheme
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unsupported URL scheme"));
-        };
+            dependency.vendor_dependencies(&vendored_path)?;
+        }
+        Ok(())
+    }
 
-        // Process the resolved path by calling from_directory (assuming this method exists)
-        Self::from_directory(&url_path).await
-    }
-
-
-    pub async fn from_directory(base_path: &Path) -> Result<Self, io::Error> {
-        debug!("Creating generator from directory: {}", base_path.display());
-        let generator_yaml: GeneratorYaml = read_yaml_file(base_path, "Generator.yaml")?;
-        let license = read_optional_file_as_string(base_path, "LICENSE");
-        let readme = read_optional_file_as_string(base_path, "README.md");
-        let values= read_yaml_file(base_path, "values.yaml")?;
-        let schema = read_optional_json_file(base_path, "values.schema.json");
-        let files = read_optional_directory(base_path, "files");
-        let templates = read_optional_directory(base_path, "templates");
-        let entities = read_optional_entities(base_path, "entities")?;
-
-        let dependencies: Vec<Generator> = match &generator_yaml.dependencies {
-            None => { vec![] }
-            Some(dependencies) => {
-                futures::future::join_all(
-                    dependencies.iter().map(|dependency| async {
-                        Generator::from_url(&dependency.repository, base_path).await.unwrap()
-                    })
-                ).await
+    /// Checks that every loaded generator satisfies the version constraints that all generators
+    /// of the tree declare for it, so that two dependents cannot silently get different versions.
+    pub fn check_version_constraints(&self) -> Result<(), ProtypoError> {
+        let mut constraints: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+        let mut loaded: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        self.collect_version_constraints(&mut constraints, &mut loaded);
+
+        let mut conflicts = vec![];
+        for (name, versions) in &loaded {
+            let Some(declared) = constraints.get(name) else { continue };
+            for version in versions {
+                let Ok(parsed) = Version::parse(version) else { continue };
+                let violated: Vec<String> = declared.iter()
+                    .filter(|(_, requirement)| parse_version_req(requirement).map_or(true, |requirement| !requirement.matches(&parsed)))
+                    .map(|(dependent, requirement)| format!("{} requires {}", dependent, requirement))
+                    .collect();
+                if !violated.is_empty() {
+                    conflicts.push(format!("{} {} is loaded but {}", name, version, violated.join(", ")));
+                }
             }
-        };
+        }
 
-        Ok(Generator {
-            base_path: base_path.to_str().unwrap().to_owned(),
-            generator_yaml,
-            license,
-            readme,
-            values,
-            schema,
-            files,
-            entities,
-            templates,
-            dependencies: Some(dependencies),
-        })
+        if conflicts.is_empty() {
+            Ok(())
+        } else {
+            Err(ProtypoError::load(self.key(), Path::new(&self.base_path).join("Generator.yaml"),
+                format!("conflicting version constraints:\n  {}", conflicts.join("\n  "))))
+        }
     }
 
-    pub fn copy_files(&self, destination_dir: &PathBuf) -> Result<(), io::Error> {
-        if !destination_dir.is_dir() {
-            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Destination directory is not a directory"));
+    fn collect_version_constraints(&self, constraints: &mut BTreeMap<String, Vec<(String, String)>>, loaded: &mut BTreeMap<String, BTreeSet<String>>) {
+        loaded.entry(self.generator_yaml.name.clone()).or_default().insert(self.generator_yaml.version.clone());
+        for dependency in self.generator_yaml.dependencies.iter().flatten() {
+            constraints.entry(dependency.name.clone()).or_default().push((self.key(), dependency.version.clone()));
+        }
+        for dependency in self.dependencies.iter().flatten() {
+            dependency.collect_version_constraints(constraints, loaded);
+        }
+    }
+
+    /// Copies the files of this generator and of its dependencies, each unique generator once
+    /// as its files do not depend on its values. Existing files the resolutions keep are left alone.
+    pub fn copy_files(&self, destination_dir: &PathBuf, resolutions: &Resolutions) -> Result<(), ProtypoError> {
+        self.copy_files_once(destination_dir, resolutions, &mut HashSet::new())
+    }
+
+    /// Plans the copies [`Generator::copy_files`] would make, without writing anything.
+    pub fn plan_files(&self, destination_dir: &Path, planner: &mut Planner) -> Result<(), ProtypoError> {
+        self.plan_files_once(destination_dir, planner, &mut HashSet::new())
+    }
+
+    fn plan_files_once(&self, destination_dir: &Path, planner: &mut Planner, planned: &mut HashSet<String>) -> Result<(), ProtypoError> {
+        if !planned.insert(self.key()) {
+            return Ok(());
         }
+        let base_path = Path::new(&self.base_path).join("files");
+        for file in self.files.iter().flatten() {
+            let file_path = Path::new(file);
+            let destination = construct_destination_path(&base_path, file_path, destination_dir)
+                .map_err(|e| ProtypoError::load(self.key(), file_path, e))?;
+            planner.add_file(&self.key(), file_path, destination)
+                .map_err(|e| ProtypoError::load(self.key(), file_path, e))?;
+        }
+        for dependency in self.dependencies.iter().flatten() {
+            dependency.plan_files_once(destination_dir, planner, planned)?;
+        }
+        Ok(())
+    }
 
+    fn copy_files_once(&self, destination_dir: &PathBuf, resolutions: &Resolutions, copied: &mut HashSet<String>) -> Result<(), ProtypoError> {
+        if !copied.insert(self.key()) {
+            debug!("{} - Files already copied", self.key());
+            return Ok(());
+        }
         if !destination_dir.exists() {
-            fs::create_dir_all(destination_dir)?;
-            debug!("{} - Creating directory {:?}",self.key(), destination_dir.parent().unwrap());
+            fs::create_dir_all(destination_dir)
+                .map_err(|e| ProtypoError::write(self.key(), destination_dir, e))?;
+            debug!("{} - Creating directory {:?}",self.key(), destination_dir);
+        }
+
+        if !destination_dir.is_dir() {
+            return Err(ProtypoError::write(self.key(), destination_dir,
+                io::Error::new(io::ErrorKind::NotFound, "Destination directory is not a directory")));
         }
 
         if self.files.is_none() {
@@ -216,52 +387,83 @@ impl Generator {
         else {
             debug!("{} - Copying files to destination {:?}", self.key(), destination_dir);
             let base_path = Path::new(&self.base_path).join("files");
-            self.files.clone().unwrap().iter().for_each(|file| {
+            for file in self.files.iter().flatten() {
                 let file_path = Path::new(file);
-                let destination = construct_destination_path(&base_path, &file_path, destination_dir).unwrap();
-                fs::create_dir_all(destination.clone().parent().unwrap()).unwrap();
-                fs::copy(&file_path, &destination).unwrap();
-            });
+                let destination = construct_destination_path(&base_path, file_path, destination_dir)
+                    .map_err(|e| ProtypoError::load(self.key(), file_path, e))?;
+                if resolutions.keeps(&destination) {
+                    continue;
+                }
+                if let Some(parent) = destination.parent() {
+                    fs::create_dir_all(parent).map_err(|e| ProtypoError::write(self.key(), parent, e))?;
+                }
+                fs::copy(file_path, &destination).map_err(|e| ProtypoError::write(self.key(), &destination, e))?;
+            }
         }
 
         if let Some(dependencies) = &self.dependencies {
             for dependency in dependencies {
-                dependency.copy_files(destination_dir)?;
+                dependency.copy_files_once(destination_dir, resolutions, copied)?;
             }
         }
 
         Ok(())
     }
 
-    pub fn generate_templates(&self, rrgen: &mut RRgen, ctx: &Context) -> Result<(), io::Error> {
+    /// The `conflicts` rules of this generator and of its dependencies with their paths compiled, by key of the generator.
+    pub fn conflict_rules(&self) -> Result<HashMap<String, Vec<(glob::Pattern, ConflictStrategy)>>, ProtypoError> {
+        let mut rules = HashMap::new();
+        self.collect_conflict_rules(&mut rules)?;
+        Ok(rules)
+    }
+
+    fn collect_conflict_rules(&self, rules: &mut HashMap<String, Vec<(glob::Pattern, ConflictStrategy)>>) -> Result<(), ProtypoError> {
+        if rules.contains_key(&self.key()) {
+            return Ok(());
+        }
+        let compiled = self.generator_yaml.conflicts.iter().flatten()
+            .map(|rule| glob::Pattern::new(&rule.path)
+                .map(|pattern| (pattern, rule.strategy))
+                .map_err(|e| ProtypoError::parse(self.key(), Path::new(&self.base_path).join("Generator.yaml"),
+                    format!("invalid conflicts path {}: {}", rule.path, e))))
+            .collect::<Result<Vec<_>, _>>()?;
+        rules.insert(self.key(), compiled);
+        for dependency in self.dependencies.iter().flatten() {
+            dependency.collect_conflict_rules(rules)?;
+        }
+        Ok(())
+    }
+
+    /// Renders the templates of the dependencies and then of this generator. A generator the tree uses
+    /// more than once, e.g. under two aliases, is rendered once for each distinct set of values it sees.
+    pub fn generate_templates(&self, renderer: &mut dyn TemplateRenderer, ctx: &Context) -> Result<(), ProtypoError> {
+        self.render_templates(renderer, ctx, None, &mut HashSet::new())
+    }
+
+    /// `parent_values` are the values the templates of the generator depending on this one see, if any.
+    fn render_templates(&self, renderer: &mut dyn TemplateRenderer, ctx: &Context, parent_values: Option<&Value>, rendered: &mut HashSet<(String, String)>) -> Result<(), ProtypoError> {
         debug!("Generator name:{:?},version:{:?}, base_path {:?}",self.generator_yaml.name, self.generator_yaml.version, self.base_path);
         debug!("Generator name:{:?},version:{:?}, Start generating templates {:?}", self.generator_yaml.name, self.generator_yaml.version, self.templates);
 
 
-        let mut generator_values= self.values.clone();
-        debug!("generator_values: {:?}", serde_json::to_string_pretty(&generator_values));
         debug!("values: {:?}", serde_json::to_string_pretty(&ctx));
-
-        if let Some(values_override) = ctx.values
-            .as_object()
-            .and_then(|value| value.get(self.generator_yaml.name.as_str())) {
-            generator_values.merge(values_override);
-        }
-
+        let generator_values = self.merged_values(ctx, parent_values);
         debug!("generator_values: {:?}", serde_json::to_string_pretty(&generator_values));
 
-        let context = serde_json::to_value(ctx).unwrap();
-
-        let mut generator_context = json!({
-            "values": self.values,
-        });
-        generator_context.merge(&context);
+        let template_values = self.template_values(ctx, &generator_values, parent_values);
+        if !rendered.insert(self.instance_key(&template_values)) {
+            debug!("{} - Templates already rendered with the same values", self.key());
+            return Ok(());
+        }
+        let mut generator_context = serde_json::to_value(ctx)
+            .map_err(|e| ProtypoError::render(self.key(), &self.base_path, e))?;
+        generator_context["values"] = template_values.clone();
         debug!("generator_context: {:?}", serde_json::to_string_pretty(&generator_context));
 
         if let Some(dependencies) = &self.dependencies {
             for dependency in dependencies {
                 debug!("Generating templates for dependency: {:?}", dependency.generator_yaml.name);
-                dependency.generate_templates(rrgen, ctx)?;
+                dependency.render_templates(renderer, ctx, Some(&template_values), rendered)?;
             }
         }
 
@@ -269,177 +471,550 @@ impl Generator {
         if self.templates.is_none() || self.templates.clone().unwrap().is_empty() {
             debug!("There are no templates to generate");
         } else {
-            rrgen.add_dir_to_tera(Path::new(&self.base_path).join("templates"));
+            let templates_dir = Path::new(&self.base_path).join("templates");
+            renderer.add_template_dir(&templates_dir)
+                .map_err(|e| ProtypoError::render(self.key(), &templates_dir, e))?;
             let mut templates = self.templates.clone().unwrap();
             templates.sort();
-            templates.iter()
-                .map(|template| Path::new(template))
-                .filter(|template| template.is_file() && !(template.file_name().unwrap().to_str().unwrap().starts_with("_") && template.extension().unwrap().to_str().unwrap().eq("tpl")))
-                .for_each(|file_path| {
-                    let file_name = file_path.file_name().unwrap().to_str().unwrap();
-                    let content = fs::read_to_string(file_path).unwrap();
-                    debug!("generating file_path:{:?}, file_name:{:?}",file_path, file_name);
-                    rrgen.generate(content.as_str(), &generator_context).unwrap();
-                });
+            let templates = templates.iter()
+                .map(Path::new)
+                .filter(|template| template.is_file() && !is_partial_template(template));
+            for file_path in templates {
+                let content = fs::read_to_string(file_path)
+                    .map_err(|e| ProtypoError::load(self.key(), file_path, e))?;
+                debug!("generating file_path:{:?}",file_path);
+                renderer.render(&self.key(), file_path, content.as_str(), &generator_context)
+                    .map_err(|e| ProtypoError::render(self.key(), file_path, e))?;
+            }
         }
 
         Ok(())
     }
 
-    fn collect_templates(&self) -> HashMap<String, Vec<String>> {
-        let mut map: HashMap<String, Vec<String>> = HashMap::new();
-        let key = format!("{}:{}", self.generator_yaml.name, self.generator_yaml.version);
-        if let Some(templates) = self.templates.clone() {
-            map.insert(key, templates.clone());
+    /// The values each generator of the tree renders its templates with, in the order they are rendered.
+    /// Generators loaded under an alias are listed as `<name>:<version> as <alias>`.
+    pub fn effective_values(&self, ctx: &Context) -> Vec<(String, Value)> {
+        let mut collected = vec![];
+        self.collect_effective_values(ctx, None, &mut collected);
+        collected
+    }
+
+    fn collect_effective_values(&self, ctx: &Context, parent_values: Option<&Value>, collected: &mut Vec<(String, Value)>) {
+        let generator_values = self.merged_values(ctx, parent_values);
+        let template_values = self.template_values(ctx, &generator_values, parent_values);
+        for dependency in self.dependencies.iter().flatten() {
+            dependency.collect_effective_values(ctx, Some(&template_values), collected);
+        }
+        let instance = match self.namespace() == self.generator_yaml.name {
+            true => self.key(),
+            false => format!("{} as {}", self.key(), self.namespace()),
+        };
+        if !collected.iter().any(|(collected_instance, values)| *collected_instance == instance && *values == template_values) {
+            collected.push((instance, template_values));
         }
+    }
 
-        if let Some(dependencies) = &self.dependencies {
-            for dep in dependencies {
-                let dep_templates = dep.collect_templates();
-                map.extend(dep_templates);
+    /// Values of this generator (`values.yaml`) merged with the values its parent sets under its
+    /// namespace and finally with the user overrides found under its namespace.
+    /// Values imported from dependencies are added beneath, so the ones of the generator take precedence.
+    pub fn merged_values(&self, ctx: &Context, parent_values: Option<&Value>) -> Value {
+        let namespace = self.namespace();
+        let mut generator_values = self.values.clone();
+        if let Some(parent_override) = parent_values.and_then(|values| values.get(namespace)) {
+            merge_values(&mut generator_values, parent_override, ctx.generate.list_merge);
+        }
+        if let Some(values_override) = ctx.values.get(namespace) {
+            merge_values(&mut generator_values, values_override, ctx.generate.list_merge);
+        }
+
+        if let Some(mut imported) = self.import_dependency_values(ctx, &generator_values, parent_values) {
+            merge_values(&mut imported, &generator_values, ctx.generate.list_merge);
+            generator_values = imported;
+        }
+        generator_values
+    }
+
+    /// Collects the values the dependencies of this generator export through their `import-values`.
+    fn import_dependency_values(&self, ctx: &Context, generator_values: &Value, parent_values: Option<&Value>) -> Option<Value> {
+        let importing: Vec<(&Generator, &Vec<ImportValue>)> = self.dependencies.iter()
+            .flatten()
+            .filter_map(|dependency| {
+                let import_values = dependency.dependency.as_ref()?.import_values.as_ref()?;
+                Some((dependency, import_values))
+            })
+            .collect();
+        if importing.is_empty() {
+            return None;
+        }
+
+        let template_values = self.template_values(ctx, generator_values, parent_values);
+        let mut imported = json!({});
+        for (dependency, import_values) in importing {
+            let dependency_values = dependency.merged_values(ctx, Some(&template_values));
+            for import_value in import_values {
+                let (child, parent) = match import_value {
+                    ImportValue::Exported(name) => (format!("exports.{}", name), String::new()),
+                    ImportValue::Mapped { child, parent } => (child.clone(), parent.clone()),
+                };
+                match value_at_path(&dependency_values, &child) {
+                    Some(value) => {
+                        debug!("{} - Importing {} of {} into {:?}", self.key(), child, dependency.key(), parent);
+                        merge_values(&mut imported, &nest_at_path(&parent, value.clone()), ctx.generate.list_merge);
+                    }
+                    None => info!("{} - Dependency {} has no value {} to import", self.key(), dependency.key(), child),
+                }
             }
         }
+        Some(imported)
+    }
 
-        map
+    /// The merged values as seen by templates, with `global` holding the global values of the generator
+    /// merged with the ones of its parent, or of the user for the root generator.
+    fn template_values(&self, ctx: &Context, generator_values: &Value, parent_values: Option<&Value>) -> Value {
+        let mut global = generator_values.get("global").cloned().unwrap_or_else(|| json!({}));
+        let inherited_global = match parent_values {
+            Some(parent_values) => parent_values.get("global"),
+            None => ctx.values.get("global"),
+        };
+        if let Some(inherited_global) = inherited_global {
+            merge_values(&mut global, inherited_global, ctx.generate.list_merge);
+        }
+
+        let mut values = generator_values.clone();
+        if let Some(values) = values.as_object_mut() {
+            values.insert("global".to_string(), global);
+        }
+        values
+    }
+
+    /// Removes the dependencies disabled by their `condition` or `tags`, recursively.
+    /// Must run before the generator is used, as the other operations walk every loaded dependency.
+    pub fn remove_disabled_dependencies(&mut self, ctx: &Context, parent_values: Option<&Value>) {
+        let generator_values = self.merged_values(ctx, parent_values);
+        let template_values = self.template_values(ctx, &generator_values, parent_values);
+        let key = self.key();
+        if let Some(dependencies) = self.dependencies.as_mut() {
+            dependencies.retain(|dependency| {
+                let enabled = dependency.is_enabled(ctx, &template_values);
+                if !enabled {
+                    info!("{} - Skipping disabled dependency {}", key, dependency.key());
+                }
+                enabled
+            });
+            for dependency in dependencies.iter_mut() {
+                dependency.remove_disabled_dependencies(ctx, Some(&template_values));
+            }
+        }
+    }
+
+    /// Evaluates the `condition` and `tags` of the dependency declaration against the values of the parent.
+    /// As in helm the first condition path that resolves to a boolean wins, then any tag set to true
+    /// in the `tags` section enables the dependency. Dependencies are enabled when neither resolves.
+    fn is_enabled(&self, ctx: &Context, parent_values: &Value) -> bool {
+        let Some(dependency) = &self.dependency else {
+            return true;
+        };
+
+        if let Some(condition) = &dependency.condition {
+            let resolved = condition.split(',')
+                .find_map(|path| value_at_path(parent_values, path).and_then(Value::as_bool));
+            if let Some(enabled) = resolved {
+                return enabled;
+            }
+            debug!("{} - condition {} does not resolve to a boolean", self.key(), condition);
+        }
+
+        if let Some(tags) = dependency.tags.as_ref().filter(|tags| !tags.is_empty()) {
+            let tag_value = |tag: &String| ctx.values.get("tags").and_then(|tags| tags.get(tag)).and_then(Value::as_bool)
+                .or_else(|| parent_values.get("tags").and_then(|tags| tags.get(tag)).and_then(Value::as_bool));
+            let resolved: Vec<bool> = tags.iter().filter_map(tag_value).collect();
+            if !resolved.is_empty() {
+                return resolved.contains(&true);
+            }
+        }
+        true
+    }
+
+    /// Validates the merged values of this generator and of all its dependencies
+    /// against their own `values.schema.json` and returns every violation found.
+    pub fn validate_values(&self, ctx: &Context) -> Result<Vec<SchemaViolation>, ProtypoError> {
+        self.validate_values_once(ctx, None, &mut HashSet::new())
+    }
+
+    fn validate_values_once(&self, ctx: &Context, parent_values: Option<&Value>, validated: &mut HashSet<(String, String)>) -> Result<Vec<SchemaViolation>, ProtypoError> {
+        let generator_values = self.merged_values(ctx, parent_values);
+        if !validated.insert(self.instance_key(&generator_values)) {
+            return Ok(vec![]);
+        }
+        let mut violations = match &self.schema {
+            Some(schema) => {
+                debug!("{} - Validating values against values.schema.json", self.key());
+                validate_values(&self.key(), schema, &generator_values)
+                    .map_err(|e| ProtypoError::schema(self.key(), Path::new(&self.base_path).join("values.schema.json"), e))?
+            }
+            None => vec![],
+        };
+
+        let template_values = self.template_values(ctx, &generator_values, parent_values);
+        if let Some(dependencies) = &self.dependencies {
+            for dependency in dependencies {
+                violations.extend(dependency.validate_values_once(ctx, Some(&template_values), validated)?);
+            }
+        }
+        Ok(violations)
     }
 
     pub(crate) fn collect_entities(&self) -> Value {
+        self.collect_entities_once(&mut HashSet::new())
+    }
+
+    fn collect_entities_once(&self, collected: &mut HashSet<String>) -> Value {
+        if !collected.insert(self.key()) {
+            return json!({});
+        }
         let mut values = self.entities.clone();
         if let Some(dependencies) = &self.dependencies {
             for dep in dependencies {
-                let entities = dep.collect_entities();
+                let entities = dep.collect_entities_once(collected);
                 values.merge(&entities);
             }
         }
         values.clone()
     }
+}
+
+/// Loads a generator tree in declaration order. Every unique generator, by [`Generator::key`],
+/// is fetched and parsed only once and a dependency cycle is reported with its path.
+struct Loader {
+    /// directories of the remote sources already fetched, by url
+    fetched: HashMap<String, PathBuf>,
+    /// generators already loaded, by key
+    loaded: HashMap<String, Generator>,
+    /// keys of the generators being loaded, from the root to the current one
+    path: Vec<String>,
+    /// how remote dependencies are verified
+    policy: FetchPolicy,
+}
+
+impl Loader {
+    fn new(policy: &FetchPolicy) -> Self {
+        Loader {
+            fetched: HashMap::new(),
+            loaded: HashMap::new(),
+            path: Vec::new(),
+            policy: policy.clone(),
+        }
+    }
 
+    fn load<'a>(&'a mut self, base_path: PathBuf, pinning: Pinning<'a>) -> BoxFuture<'a, Result<Generator, ProtypoError>> {
+        Box::pin(async move {
+            let base_path = base_path.as_path();
+            debug!("Creating generator from directory: {}", base_path.display());
+            let generator_yaml: GeneratorYaml = read_yaml_file(&base_path.display().to_string(), base_path, "Generator.yaml")?;
+            let key = format!("{}:{}", generator_yaml.name, generator_yaml.version);
+
+            if let Some(position) = self.path.iter().position(|ancestor| *ancestor == key) {
+                let mut cycle = self.path[position..].to_vec();
+                cycle.push(key.clone());
+                return Err(ProtypoError::load(key, base_path, format!("dependency cycle detected: {}", cycle.join(" -> "))));
+            }
+            if let Some(generator) = self.loaded.get(&key) {
+                debug!("{} - Already loaded from {}", key, generator.base_path);
+                return Ok(generator.clone());
+            }
 
-    fn read_dir_to_vec(dir_path: impl AsRef<Path>) -> Result<Vec<String>, io::Error> {
-        let mut file_names = Vec::new();
-        if dir_path.as_ref().exists() {
-            for entry in fs::read_dir(dir_path)? {
-                let entry = entry?;
-                if entry.file_type()?.is_file() {
-                    if let Some(file_name) = entry.file_name().to_str() {
-                        file_names.push(file_name.to_string());
+            let license = read_optional_file_as_string(base_path, "LICENSE");
+            let readme = read_optional_file_as_string(base_path, "README.md");
+            let mut values: Value = read_yaml_file(&key, base_path, "values.yaml")?;
+            let schema = read_optional_json_file(&key, base_path, "values.schema.json")?;
+            if let Some(defaults) = schema.as_ref().and_then(schema_defaults) {
+                debug!("Applying defaults from values.schema.json: {:?}", defaults);
+                if values.is_null() {
+                    values = json!({});
+                }
+                fill_defaults(&mut values, &defaults);
+            }
+            let files = read_optional_directory(base_path, "files")
+                .map_err(|e| ProtypoError::load(&key, base_path.join("files"), e))?;
+            let templates = read_optional_directory(base_path, "templates")
+                .map_err(|e| ProtypoError::load(&key, base_path.join("templates"), e))?;
+            let entities = read_optional_entities(base_path, "entities")
+                .map_err(|e| ProtypoError::load(&key, base_path.join("entities"), e))?;
+
+            let lock = match pinning {
+                Pinning::Lockfile => read_lock(base_path).map_err(|e| ProtypoError::parse(&key, base_path.join(LOCK_FILE), e))?,
+                _ => None,
+            };
+            if let Some(lock) = &lock {
+                if !lock.is_up_to_date(generator_yaml.dependencies.as_deref().unwrap_or_default()) {
+                    info!("{} is out of date with the dependencies of {}, run `protypo dependency update`", LOCK_FILE, base_path.display());
+                }
+            }
+            let locked = match pinning {
+                Pinning::Lockfile => lock.as_ref().map(|lock| lock.dependencies.as_slice()),
+                Pinning::Locked(locked) => Some(locked),
+                Pinning::Unlocked => None,
+            };
+
+            self.path.push(key.clone());
+            let mut dependencies = vec![];
+            for dependency in generator_yaml.dependencies.iter().flatten() {
+                let locked_dependency = locked.and_then(|locked| locked.iter().find(|locked| locked.locks(dependency)));
+                if locked.is_some() && locked_dependency.is_none() {
+                    info!("Dependency {} of {} is not locked, run `protypo dependency update`", dependency.name, base_path.display());
+                }
+                let dependency_pinning = match (locked_dependency, &pinning) {
+                    (Some(locked_dependency), _) => Pinning::Locked(&locked_dependency.dependencies),
+                    (None, Pinning::Unlocked) => Pinning::Unlocked,
+                    (None, _) => Pinning::Lockfile,
+                };
+                let loaded = self.load_dependency(dependency, base_path, locked_dependency, dependency_pinning).await;
+                match loaded {
+                    Ok(generator) => dependencies.push(generator),
+                    Err(e) => {
+                        self.path.pop();
+                        return Err(e);
                     }
                 }
             }
-        }
-        Ok(file_names)
+            self.path.pop();
+            debug!("{} - Dependencies: {:?}", key, dependencies.iter().map(Generator::key).collect::<Vec<_>>());
+
+            let generator = Generator {
+                base_path: base_path.to_string_lossy().into_owned(),
+                generator_yaml,
+                license,
+                readme,
+                values,
+                schema,
+                files,
+                entities,
+                templates,
+                dependencies: Some(dependencies),
+                dependency: None,
+            };
+            self.loaded.insert(key, generator.clone());
+            Ok(generator)
+        })
     }
-}
 
-/// Downloads and extracts an archive (ZIP or TAR.GZ) from a URL.
-async fn download_and_extract_to_temp(url: Url) -> Result<PathBuf, io::Error> {
-    let temp_dir = tempdir().unwrap().into_path();
-    debug!("Downloading to {:?} to {:?}", url,temp_dir);
+    /// Loads a dependency from the vendored `generators` directory of its parent, from its url,
+    /// or else the highest matching version from the local repository,
+    /// and checks that the loaded version satisfies the version constraint of the dependency.
+    /// A locked dependency is loaded from its locked url or exact version and must match the locked digest.
+    async fn load_dependency(&mut self, dependency: &Dependency, base_path: &Path, locked_dependency: Option<&LockedDependency>, pinning: Pinning<'_>) -> Result<Generator, ProtypoError> {
+        let parent = self.path.last().cloned().unwrap_or_else(|| base_path.display().to_string());
+        let requirement = parse_version_req(&dependency.version)
+            .map_err(|e| ProtypoError::parse(&parent, base_path.join("Generator.yaml"), e))?;
+        let vendored_requirement = match locked_dependency {
+            Some(locked_dependency) => parse_version_req(&locked_dependency.version)
+                .map_err(|e| ProtypoError::parse(&parent, base_path.join(LOCK_FILE), e))?,
+            None => requirement.clone(),
+        };
+        let resolve_local = |requirement: &VersionReq| -> Result<PathBuf, ProtypoError> {
+            let local_generators_dir = local_generators_dir()
+                .map_err(|e| ProtypoError::load(&parent, base_path, e))?;
+            resolve_installed(&local_generators_dir, &dependency.name, Some(requirement))
+                .map(|(_, path)| path)
+                .map_err(|e| ProtypoError::load(&parent, &local_generators_dir, e))
+        };
+        // resolving the tree again must look at the sources, not at what was vendored before
+        let vendored = match pinning {
+            Pinning::Unlocked => None,
+            _ => resolve_installed(&base_path.join(VENDOR_DIR), &dependency.name, Some(&vendored_requirement)).ok(),
+        };
+        if let Some((version, _)) = &vendored {
+            debug!("Using vendored dependency {} {} of {}", dependency.name, version, base_path.display());
+        }
 
-    let response = reqwest::get(url.clone()).await.map_err(|e| io::Error::new(ErrorKind::Other, format!("Failed to download file {} due to error: {}", url.to_string(), e)))?;
+        let path = match (vendored, locked_dependency) {
+            (Some((_, vendored_path)), _) => vendored_path,
+            (None, Some(locked_dependency)) => match &locked_dependency.url {
+                Some(url) => self.fetch(&parent, url, base_path, &vendored_requirement).await?,
+                None => resolve_local(&vendored_requirement)?,
+            },
+            (None, None) => match &dependency.repository {
+                Some(url) => self.fetch(&parent, url, base_path, &requirement).await?,
+                None => resolve_local(&requirement)?,
+            },
+        };
 
-    if !response.status().is_success() {
-        return Err(io::Error::new(ErrorKind::Other, format!("Failed to download file from {}. Status code: {}", url, response.status())));
-    }
+        if let Some(locked_dependency) = locked_dependency {
+            let digest = directory_digest(&path)
+                .map_err(|e| ProtypoError::load(&parent, &path, e))?;
+            if digest != locked_dependency.digest {
+                let source = locked_dependency.url.as_ref().map(Url::to_string).unwrap_or_else(|| path.display().to_string());
+                return Err(ProtypoError::fetch(&parent, source, format!("digest of dependency {} {} does not match {}: expected {}, got {}",
+                    dependency.name, locked_dependency.version, LOCK_FILE, locked_dependency.digest, digest)));
+            }
+        }
+        let mut generator = self.load(path, pinning).await?;
 
-    let bytes = response.bytes().await.map_err(|e| io::Error::new(ErrorKind::Other, format!("Failed to read response bytes of file downloaded from url {} due to error: {}", url.to_string(), e)))?;
+        let version = Version::parse(&generator.generator_yaml.version)
+            .map_err(|e| ProtypoError::parse(generator.key(), Path::new(&generator.base_path).join("Generator.yaml"), format!("invalid version: {}", e)))?;
+        if !requirement.matches(&version) {
+            return Err(ProtypoError::load(&parent, &generator.base_path, format!("dependency {} requires version {} but {} was loaded",
+                dependency.name, requirement, version)));
+        }
+        generator.dependency = Some(dependency.clone());
+        Ok(generator)
+    }
 
-    let cursor = Cursor::new(bytes);
-    if url.to_string().ends_with(".zip") || url.to_string().ends_with(".tar.gz") {
-        let mut zip = ZipArchive::new(cursor)?;
-        zip.extract(&temp_dir)?;
+    /// Fetches a url once per load, the same remote dependency of several generators is downloaded only once.
+    /// `repo://` urls are resolved through the cached repository indexes to a version matching `requirement`.
+    async fn fetch(&mut self, generator: &str, url: &Url, base_path: &Path, requirement: &VersionReq) -> Result<PathBuf, ProtypoError> {
+        if url.scheme() == "file" {
+            return fetch(url, base_path, &self.policy).await.map_err(|e| ProtypoError::fetch(generator, url, e));
+        }
+        let key = match url.scheme() {
+            "repo" => format!("{} {}", url, requirement),
+            _ => url.to_string(),
+        };
+        if let Some(path) = self.fetched.get(&key) {
+            debug!("Already fetched {} into {}", url, path.display());
+            return Ok(path.clone());
+        }
+        let path = match url.scheme() {
+            "repo" => {
+                let data_dir = local_data_dir().map_err(|e| ProtypoError::fetch(generator, url, e))?;
+                fetch_from_repository(&data_dir, url, requirement, &self.policy).await
+            }
+            _ => fetch(url, base_path, &self.policy).await,
+        }.map_err(|e| ProtypoError::fetch(generator, url, e))?;
+        self.fetched.insert(key, path.clone());
+        Ok(path)
     }
+}
 
-    Ok(temp_dir)
+/// Resolves a `file://` url relative to `base_path` or downloads an `http(s)://` one,
+/// returning the directory that contains the generator. Packaged generators are verified as the policy asks,
+/// and generators from hosts that are not trusted are only used once the user confirms them.
+async fn fetch(url: &Url, base_path: &Path, policy: &FetchPolicy) -> Result<PathBuf, io::Error> {
+    let needs_consent = check_source(policy, url.as_str())?;
+    let path = fetch_source(url, base_path, policy).await?;
+    if needs_consent {
+        confirm_source(policy, url.as_str(), &path)?;
+    }
+    Ok(path)
 }
 
+async fn fetch_source(url: &Url, base_path: &Path, policy: &FetchPolicy) -> Result<PathBuf, io::Error> {
+    if url.scheme() == "file" {
+        let url = url.to_string();
+        debug!("url: {}", url);
+        let file_path = url.strip_prefix("file://").unwrap_or(&url).to_string();
+        debug!("Using url is filesystem path: {}", file_path);
+        let path = base_path.join(&file_path);
+        if path.is_file() {
+            // a packaged generator
+            extract_local_archive(&path, policy).await
+        } else {
+            check_unsigned_source(policy, url.as_str())?;
+            Ok(path)
+        }
+    } else if url.scheme() == "http" || url.scheme() == "https" {
+        // For http:// or https:// URLs, handle download and return a path to the downloaded file
+        download_and_extract(url, policy).await
+    } else if is_git_uri(url.as_str()) {
+        check_unsigned_source(policy, url.as_str())?;
+        let source = GitSource::parse(url.as_str())?;
+        let (path, commit) = checkout_cached(policy, &source, base_path)?;
+        debug!("Resolved {} to commit {}", url, commit);
+        Ok(path)
+    } else {
+        // Unsupported scheme
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "Unsupported URL scheme"))
+    }
+}
 
-fn construct_destination_path(base_path: &Path, file: &Path, destination_dir: &Path) -> Result<PathBuf, io::Error> {
-    let base_path = base_path.canonicalize().map_err(|e| {
-        eprintln!("Error canonicalizing base_path: {:?}", e);
-        e
-    })?;
 
-    let file = file.canonicalize().map_err(|e| {
-        eprintln!("Error canonicalizing file: {:?}", e);
-        e
-    })?;
 
-    let destination_file_path = file.strip_prefix(&base_path).map_err(|e| {
-        eprintln!("Error stripping prefix from file: {:?}", e);
-        io::Error::new(io::ErrorKind::Other, "Strip prefix failed")
-    })?;
-    let destination = destination_dir.join(destination_file_path);
-    Ok(destination)
+/// Where a file of the `files` directory `base_path` of a generator is copied to, at the same relative path below `destination_dir`.
+fn construct_destination_path(base_path: &Path, file: &Path, destination_dir: &Path) -> Result<PathBuf, io::Error> {
+    let canonical_base_path = base_path.canonicalize()
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot resolve {}: {}", base_path.display(), e)))?;
+    let canonical_file = file.canonicalize()
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot resolve {}: {}", file.display(), e)))?;
+    let destination_file_path = canonical_file.strip_prefix(&canonical_base_path)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("{} is not inside {}", file.display(), base_path.display())))?;
+    Ok(destination_dir.join(destination_file_path))
 }
 
-fn read_yaml_file<T: for<'de> Deserialize<'de>>(base_path: &Path, file_name: &str) -> Result<T, io::Error> {
+fn read_yaml_file<T: for<'de> Deserialize<'de>>(generator: &str, base_path: &Path, file_name: &str) -> Result<T, ProtypoError> {
     let file_path = base_path.join(file_name);
-    let content = fs::read_to_string(file_path.clone())
-        .map_err(|e| io::Error::new(io::ErrorKind::NotFound, format!("Error reading file {:?} due to the following error:{:?}:", file_path, e)))?;
+    let content = fs::read_to_string(&file_path)
+        .map_err(|e| ProtypoError::load(generator, &file_path, e))?;
 
     let data: T = serde_yaml::from_str(&content)
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Cannot deserialize file {:?} due to error:{:?}", file_path, e)))?;
+        .map_err(|e| ProtypoError::parse(generator, &file_path, e))?;
     Ok(data)
 }
 
+/// Templates named `_*.tpl` only hold definitions for other templates and are not rendered themselves.
+fn is_partial_template(template: &Path) -> bool {
+    let partial_name = template.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('_'));
+    partial_name && template.extension().is_some_and(|extension| extension == "tpl")
+}
+
 fn read_optional_file_as_string(base_path: &Path, file_name: &str) -> Option<String> {
     fs::read_to_string(base_path.join(file_name)).ok()
 }
 
-fn read_optional_json_file(base_path: &Path, file_name: &str) -> Option<serde_json::Value> {
-    let content = fs::read_to_string(base_path.join(file_name)).ok()?;
-    serde_json::from_str(&content).ok()
+/// `None` when the file does not exist, an error when it exists but cannot be read or parsed.
+fn read_optional_json_file(generator: &str, base_path: &Path, file_name: &str) -> Result<Option<Value>, ProtypoError> {
+    let file_path = base_path.join(file_name);
+    if !file_path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(&file_path)
+        .map_err(|e| ProtypoError::load(generator, &file_path, e))?;
+    serde_json::from_str(&content)
+        .map(Some)
+        .map_err(|e| ProtypoError::parse(generator, &file_path, e))
 }
 
-fn read_optional_directory(base_path: &Path, dir_name: &str) -> Option<Vec<String>> {
+/// Every path below `dir` matching `pattern`, with `dir` itself escaped so that glob metacharacters in it are taken literally.
+pub(crate) fn glob_below(dir: &Path, pattern: &str) -> Result<glob::Paths, io::Error> {
+    let dir = dir.to_str()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("Path {} is not valid UTF-8", dir.display())))?;
+    let glob_pattern = Path::new(&glob::Pattern::escape(dir)).join(pattern);
+    glob(&glob_pattern.to_string_lossy())
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))
+}
+
+fn read_optional_directory(base_path: &Path, dir_name: &str) -> Result<Option<Vec<String>>, io::Error> {
     let dir_path = base_path.join(dir_name);
     if !dir_path.exists() || !dir_path.is_dir() {
-        return None;
+        return Ok(None);
     }
 
-    let glob_pattern = base_path.join(dir_name).join("**/*");
-
-    let files: Vec<String> = glob(glob_pattern.to_str().unwrap())
-        .unwrap()
-        .filter_map(|x| {
-            match x {
-                Ok(path) if path.is_file() => {
-                    path.to_str().map(|s| s.to_string())
-                }
-                Ok(_) => None,
-                Err(e) => {
-                    eprintln!("Error: {}", e);
-                    None
-                }
-            }
-        })
-        .filter(|s| !s.is_empty())
-        .collect::<Vec<String>>();
+    let mut files = vec![];
+    for entry in glob_below(&dir_path, "**/*")? {
+        let path = entry.map_err(io::Error::from)?;
+        if path.is_file() {
+            let path = path.to_str()
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("Path {} is not valid UTF-8", path.display())))?;
+            files.push(path.to_string());
+        }
+    }
 
     if files.is_empty() {
-        None
+        Ok(None)
     } else {
-        Some(files)
+        Ok(Some(files))
     }
 }
 
-fn read_optional_entities(base_path: &Path, dir_name: &str) -> Result<Value, io::Error> {
+pub(crate) fn read_optional_entities(base_path: &Path, dir_name: &str) -> Result<Value, io::Error> {
     let dir_path = base_path.join(dir_name);
     if !dir_path.exists() || !dir_path.is_dir() {
         return Ok(json!({}));
     }
 
-    let files_path = base_path.join(dir_name.clone()).join("**/*.schema.json");
-    let glob_pattern = files_path.to_str().ok_or_else(|| {
-        io::Error::new(ErrorKind::InvalidInput, "Failed to convert pattern to string")
-    })?;
-
-
     let schemas: Map<String, Value> =
-        glob(glob_pattern)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
-            .into_iter()
+        glob_below(&dir_path, "**/*.schema.json")?
             .filter_map(|file_path_result| {
                 let file_path = file_path_result.ok()?;
                 let content = fs::read_to_string(&file_path).ok()?;
@@ -452,86 +1027,104 @@ fn read_optional_entities(base_path: &Path, dir_name: &str) -> Result<Value, io:
 }
 
 
-fn read_required_directory(base_path: &Path, dir_name: &str) -> Result<Vec<String>, io::Error> {
-    let dir_path = base_path.join(dir_name);
-    if !dir_path.exists() || !dir_path.is_dir() {
-        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Directory {} not found", dir_name)));
+/// Checks that a directory holds a well-formed generator before it is installed or packaged:
+/// a `Generator.yaml` with a usable name, a semantic version and valid dependency constraints,
+/// and a parseable `values.yaml` and `values.schema.json` when present.
+pub fn validate_generator(generator_dir_path: &Path) -> Result<GeneratorYaml, ProtypoError> {
+    debug!("Starting validation of {}", generator_dir_path.display());
+    let generator: GeneratorYaml = read_yaml_file(&generator_dir_path.display().to_string(), generator_dir_path, "Generator.yaml")?;
+    let key = format!("{}:{}", generator.name, generator.version);
+    let generator_yaml_path = generator_dir_path.join("Generator.yaml");
+    if generator.name.is_empty() || generator.name.contains(['/', '\\', '@']) || generator.name.starts_with('.') {
+        return Err(ProtypoError::parse(&key, &generator_yaml_path, format!("invalid generator name '{}'", generator.name)));
+    }
+    Version::parse(&generator.version)
+        .map_err(|e| ProtypoError::parse(&key, &generator_yaml_path, format!("invalid version '{}': {}", generator.version, e)))?;
+    for dependency in generator.dependencies.iter().flatten() {
+        parse_version_req(&dependency.version)
+            .map_err(|e| ProtypoError::parse(&key, &generator_yaml_path, format!("dependency {}: {}", dependency.name, e)))?;
     }
-    let files = fs::read_dir(dir_path)?
-        .filter_map(|entry| entry.ok().map(|e| e.path().display().to_string()))
-        .collect();
-    Ok(files)
-}
 
-async fn validate_generator(generator_dir_path: PathBuf) {
-    debug!("Starting validation of {}",generator_dir_path.to_str().unwrap());
-    debug!("TODO!");
-    todo!()
+    if generator_dir_path.join("values.yaml").is_file() {
+        read_yaml_file::<Value>(&key, generator_dir_path, "values.yaml")?;
+    }
+    let schema_path = generator_dir_path.join("values.schema.json");
+    if schema_path.is_file() {
+        let content = fs::read_to_string(&schema_path).map_err(|e| ProtypoError::load(&key, &schema_path, e))?;
+        let schema: Value = serde_json::from_str(&content).map_err(|e| ProtypoError::parse(&key, &schema_path, e))?;
+        jsonschema::validator_for(&schema).map_err(|e| ProtypoError::schema(&key, &schema_path, e))?;
+    }
+    Ok(generator)
 }
 
-async fn prepare_generator_source(uri: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+/// Returns the directory of the generator at `uri` and, for git sources, the commit that was checked out.
+async fn prepare_generator_source(uri: &str, policy: &FetchPolicy) -> Result<(PathBuf, Option<String>), ProtypoError> {
     let path = Path::new(uri);
     if path.is_dir() {
         debug!("Uri is local directory: {:?}", path.display());
-        Ok(Path::new(uri).to_path_buf())
+        check_unsigned_source(policy, uri).map_err(|e| ProtypoError::fetch(uri, uri, e))?;
+        Ok((Path::new(uri).to_path_buf(), None))
+    } else if is_git_uri(uri) {
+        info!("Detected git URI, cloning repo...");
+        check_unsigned_source(policy, uri).map_err(|e| ProtypoError::fetch(uri, uri, e))?;
+        let source = GitSource::parse(uri).map_err(|e| ProtypoError::fetch(uri, uri, e))?;
+        let (path, commit) = checkout_cached(policy, &source, Path::new("")).map_err(|e| ProtypoError::fetch(uri, uri, e))?;
+        Ok((path, Some(commit)))
     } else {
-        let temp_dir = tempdir().unwrap().into_path();
-        debug!("Created temporary directory: {:?}", temp_dir);
-        if uri.starts_with("https://github.com") {
-            if uri.ends_with(".zip") || uri.ends_with(".tar.gz") {
-                info!("Detected GitHub directory URL that is not a repo, downloading specific directory...");
-                download_and_extract(uri, &temp_dir);
-            } else {
-                info!("Detected GitHub directory URL that is a repo, cloning repo...");
-                clone_git_repo(uri, &temp_dir)?;
-            }
-            Ok(temp_dir)
-        } else if uri.ends_with(".zip") || uri.ends_with(".tar.gz") {
+        if path.is_file() {
+            info!("Detected archive file, extracting...");
+            let dir = extract_local_archive(path, policy).await.map_err(|e| ProtypoError::fetch(uri, uri, e))?;
+            return Ok((dir, None));
+        }
+        let url = Url::parse(uri).map_err(|e| ProtypoError::fetch(uri, uri, e))?;
+        if url.host_str() == Some("github.com") && !is_archive_url(&url) {
+            info!("Detected GitHub directory URL that is a repo, cloning repo...");
+            check_unsigned_source(policy, uri).map_err(|e| ProtypoError::fetch(uri, uri, e))?;
+            let source = GitSource::parse(&format!("git+{}", uri)).map_err(|e| ProtypoError::fetch(uri, uri, e))?;
+            let (path, commit) = checkout_cached(policy, &source, Path::new("")).map_err(|e| ProtypoError::fetch(uri, uri, e))?;
+            Ok((path, Some(commit)))
+        } else if url.scheme() == "http" || url.scheme() == "https" {
             info!("Detected URL, downloading file...");
-            download_and_extract(uri, &temp_dir);
-            Ok(temp_dir)
+            let dir = download_and_extract(&url, policy).await.map_err(|e| ProtypoError::fetch(uri, uri, e))?;
+            Ok((dir, None))
         } else {
-            return Err("Unsupported URI format".into());
+            Err(ProtypoError::fetch(uri, uri, "unsupported URI format"))
         }
     }
 }
 
-/// Downloads and extracts an archive (ZIP or TAR.GZ) from a URL.
-async fn download_and_extract(uri: &str, extract_to: &Path) -> Result<(), Box<dyn std::error::Error>> {
-    let response = reqwest::get(uri).await?;
-
-    let file_path = extract_to.join("download.zip");
-
-    let mut file = File::create(&file_path).await.unwrap();
-    let content = response.text().await.unwrap();
-    file.write_all(content.as_bytes());
-    let file = fs::File::open(file_path).unwrap();
+/// Whether the path of `url` names an archive rather than a repository.
+fn is_archive_url(url: &Url) -> bool {
+    [".zip", ".tar", ".tar.gz", ".tgz", ".tar.xz", ".txz", ".tar.zst", ".tzst"].iter()
+        .any(|suffix| url.path().ends_with(suffix))
+}
 
-    if uri.ends_with(".zip") {
-        let mut zip = ZipArchive::new(file).unwrap();
-        zip.extract(extract_to)?;
-    } else if uri.ends_with(".tar.gz") {
-        let buffered_file = BufReader::new(file);
-        let tar_gz = GzDecoder::new(buffered_file);
-        let mut archive = Archive::new(tar_gz);
-        archive.unpack(extract_to)?;
-    } else {
-        return Err("Unsupported archive format".into());
-    }
+/// Downloads an archive from a URL through the download cache and extracts it, see [`crate::archive::extract`] for the supported formats.
+/// Returns the directory of the extracted archive in the cache.
+async fn download_and_extract(url: &Url, policy: &FetchPolicy) -> Result<PathBuf, io::Error> {
+    debug!("Downloading {}", url);
+    let bytes = read_cached(policy, url).await?;
+    let extracted_dir = extract_cached(&policy.data_dir, &bytes)?;
+    check_signature(policy, url, &bytes, &extracted_dir).await?;
+    Ok(extracted_dir)
+}
 
-    Ok(())
+/// Extracts a packaged generator on the local filesystem into the cache, verifying it as the policy asks.
+async fn extract_local_archive(path: &Path, policy: &FetchPolicy) -> Result<PathBuf, io::Error> {
+    let bytes = fs::read(path)?;
+    let extracted_dir = extract_cached(&policy.data_dir, &bytes)?;
+    check_signature(policy, &file_url(path)?, &bytes, &extracted_dir).await?;
+    Ok(extracted_dir)
 }
 
-/// Clones a Git repository to a temporary directory.
-fn clone_git_repo(repo_url: &str, clone_to: &Path) -> Result<(), Box<dyn std::error::Error>> {
-    Repository::clone(repo_url, clone_to)?;
-    Ok(())
+/// The `file://` url of a local archive, so its provenance file is looked up like a downloaded one's.
+fn file_url(path: &Path) -> Result<Url, io::Error> {
+    Url::from_file_path(path.canonicalize()?)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("Invalid archive path {}", path.display())))
 }
 
 /// Copies a local file or folder to the temporary directory.
-fn copy_local_path(src: &str, dest: &Path) -> Result<(), Box<dyn std::error::Error>> {
-    let src_path = Path::new(src);
-
+fn copy_local_path(src_path: &Path, dest: &Path) -> Result<(), io::Error> {
     if src_path.is_dir() {
         // Recursively copy the directory
         fs::create_dir_all(dest)?;
@@ -541,72 +1134,442 @@ fn copy_local_path(src: &str, dest: &Path) -> Result<(), Box<dyn std::error::Err
             let dest_path = dest.join(entry.file_name());
 
             if entry_path.is_dir() {
-                fs::create_dir_all(&dest_path)?;
-                copy_local_path(entry_path.to_str().unwrap(), &dest_path)?;
+                copy_local_path(&entry_path, &dest_path)?;
             } else {
                 fs::copy(&entry_path, &dest_path)?;
             }
         }
-    } else if src_path.is_file() {
+    } else if let (true, Some(file_name)) = (src_path.is_file(), src_path.file_name()) {
         // Copy the file
-        fs::copy(src_path, dest.join(src_path.file_name().unwrap()))?;
+        fs::copy(src_path, dest.join(file_name))?;
     } else {
-        return Err("Invalid source path".into());
+        return Err(io::Error::new(ErrorKind::NotFound, format!("Invalid source path {}", src_path.display())));
     }
 
     Ok(())
 }
 
 /// Moves the generator folder to the repository root after validation.
-async fn move_to_repo_root(temp_dir: PathBuf, repo_root: &PathBuf) -> Result<(), io::Error> {
-    let path = temp_dir.clone().join("Generator.yaml");
-    debug!("Path: {}", path.display());
-    let mut file = File::open(path.clone()).await.unwrap();
-
-    // Read the file contents asynchronously into a String
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).await?;
-
-    // Deserialize from the string contents
-    let generator: GeneratorYaml = serde_yaml::from_str(&contents)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Failed to deserialize Generator.yaml"))?;
-
-    let generator_dir = Path::new(repo_root).join(generator.name.clone()).join(generator.version.clone());
-
-    info!("Installing generator with name:{}, version:{} to directory {}",generator.name.clone(),generator.version.clone(),generator_dir.display());
-    if !generator_dir.exists() {
-        create_dir_all(&generator_dir);
-    }
-
-    // use glob for installing templates
-    // for file in WalkDir::new(temp_dir.clone()).into_iter().filter_map(|file| file.ok()) {
-    //     if file.file_type().is_file() {
-    //         let source = file.clone().into_path();
-    //         let stripped_path = file.path().strip_prefix(temp_dir.clone());
-    //         let destination = generator_dir.clone().join(stripped_path.unwrap());
-    //         fs::create_dir_all(destination.parent().unwrap())?;
-    //         debug!("Copying file {} to {}", source.display(),destination.display());
-    //         copy(source, destination).await.unwrap();
-    //     }
-    // }
+/// The tree is copied into a staging directory next to its destination first and then renamed,
+/// so an interrupted install never leaves a partially copied version behind.
+fn move_to_repo_root(source_dir: &Path, repo_root: &Path, force: bool, metadata: &InstallMetadata) -> Result<(GeneratorYaml, PathBuf), ProtypoError> {
+    let generator = validate_generator(source_dir)?;
+    let key = format!("{}:{}", generator.name, generator.version);
+
+    let name_dir = repo_root.join(&generator.name);
+    let generator_dir = name_dir.join(&generator.version);
+    if generator_dir.exists() && !force {
+        return Err(ProtypoError::write(&key, &generator_dir, io::Error::new(ErrorKind::AlreadyExists,
+            format!("generator {} {} is already installed, use --force to overwrite it", generator.name, generator.version))));
+    }
+
+    info!("Installing generator with name:{}, version:{} to directory {}", generator.name, generator.version, generator_dir.display());
+    fs::create_dir_all(&name_dir).map_err(|e| ProtypoError::write(&key, &name_dir, e))?;
+    let staging_dir = tempfile::Builder::new()
+        .prefix(".staging-")
+        .tempdir_in(&name_dir)
+        .map_err(|e| ProtypoError::write(&key, &name_dir, e))?;
+    debug!("Copying {} to staging directory {}", source_dir.display(), staging_dir.path().display());
+    copy_local_path(source_dir, staging_dir.path())
+        .map_err(|e| ProtypoError::write(&key, staging_dir.path(), e))?;
+    write_install_metadata(staging_dir.path(), metadata)
+        .map_err(|e| ProtypoError::write(&key, staging_dir.path().join(INSTALL_METADATA), e))?;
+
+    if generator_dir.exists() {
+        // keep the installed version until the new one is in place, so it can be restored if the rename fails
+        let previous_dir = tempfile::Builder::new()
+            .prefix(".previous-")
+            .tempdir_in(&name_dir)
+            .map_err(|e| ProtypoError::write(&key, &name_dir, e))?;
+        let previous_path = previous_dir.path().join(&generator.version);
+        debug!("Replacing installed version {}", generator_dir.display());
+        fs::rename(&generator_dir, &previous_path).map_err(|e| ProtypoError::write(&key, &generator_dir, e))?;
+        if let Err(e) = fs::rename(staging_dir.path(), &generator_dir) {
+            fs::rename(&previous_path, &generator_dir).map_err(|e| ProtypoError::write(&key, &generator_dir, e))?;
+            return Err(ProtypoError::write(&key, &generator_dir, e));
+        }
+    } else {
+        fs::rename(staging_dir.path(), &generator_dir).map_err(|e| ProtypoError::write(&key, &generator_dir, e))?;
+    }
+
+    Ok((generator, generator_dir))
+}
+
+/// Replaces each entity of the config that is only a `$ref` with the content of the referenced file,
+/// resolved relative to `parent_path`.
+pub(crate) fn dereference_config(config: &mut Value, parent_path: &Path) -> Result<(), anyhow::Error> {
+    let Some(entities) = config.get_mut("entities").and_then(Value::as_object_mut) else {
+        return Ok(());
+    };
+
+    for (name, elem) in entities.iter_mut() {
+        let object = elem.as_object_mut()
+            .ok_or_else(|| anyhow!("entity {} must be a mapping", name))?;
+        let Some(reference) = object.get("$ref").and_then(Value::as_str).filter(|_| object.len() == 1) else {
+            continue;
+        };
+        debug!("loading file from reference:{reference}");
+        let file_path = parent_path.join(reference);
+        if !file_path.is_file() {
+            return Err(anyhow!("entity {} references {} which does not exist", name, file_path.display()));
+        }
+        *elem = path_to_json(&file_path)
+            .map_err(|e| anyhow!("entity {} references {}: {}", name, file_path.display(), e))?;
+    }
     Ok(())
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UserConfig;
+    use crate::signing::Keyring;
+    use crate::lock::{write_lock, GeneratorLock};
+
+    /// Writes a generator with the given dependencies, as YAML, and a template printing its `name` value.
+    fn write_generator(dir: &Path, name: &str, dependencies: &str, values: &str) {
+        fs::create_dir_all(dir.join("templates")).unwrap();
+        fs::write(dir.join("Generator.yaml"), format!("apiVersion: v1\nname: {}\nversion: 1.0.0\n{}", name, dependencies)).unwrap();
+        fs::write(dir.join("values.yaml"), values).unwrap();
+        fs::write(dir.join("templates").join(format!("{}.t", name)), "{{ values.name }}").unwrap();
+    }
 
-pub(crate) fn dereference_config(config: &mut Value, parent_path: &Path) {
-    // debug!("dereferencing config:{config}");
-    let entities = config.get_mut("entities").unwrap().as_object_mut().unwrap();
+    fn depends_on(names: &[&str]) -> String {
+        let dependencies: String = names.iter().map(|name| format!("- name: {}\n  version: ^1\n", name)).collect();
+        format!("dependencies:\n{}", dependencies)
+    }
 
-    entities.values_mut().into_iter().for_each(|elem| {
-        let object = elem.as_object_mut().unwrap();
-        if object.contains_key("$ref") && object.get("$ref").unwrap().is_string() && object.len() == 1 {
-            let reference = object.get("$ref").unwrap().as_str().unwrap();
-            debug!("loading file from reference:{reference}");
-            let file_path = parent_path.join(reference);
-            if file_path.exists() {
-                *elem = path_to_json(&file_path).expect(format!("file {} doesnt exist or is an invalid JSON", file_path.display()).as_str());
-            } else {
-                error!("File {} does not exist",file_path.display());
-            }
+    fn vendored(dir: &Path, name: &str) -> PathBuf {
+        dir.join(VENDOR_DIR).join(name).join("1.0.0")
+    }
+
+    async fn load(dir: &Path) -> Result<Generator, ProtypoError> {
+        let policy = FetchPolicy::new(dir, &UserConfig::default(), false, Keyring::default());
+        Generator::from_directory(dir, &policy).await
+    }
+
+    /// A generator in memory with the given values, declared by its parent with the given YAML if any.
+    fn generator(name: &str, values: Value, declaration: Option<&str>, dependencies: Vec<Generator>) -> Generator {
+        Generator {
+            base_path: String::new(),
+            generator_yaml: serde_yaml::from_str(&format!("apiVersion: v1\nname: {}\nversion: 1.0.0\n", name)).unwrap(),
+            license: None,
+            readme: None,
+            values,
+            schema: None,
+            files: None,
+            entities: json!({}),
+            templates: None,
+            dependencies: Some(dependencies),
+            dependency: declaration.map(|declaration| serde_yaml::from_str(declaration).unwrap()),
         }
-    });
-}
\ No newline at end of file
+    }
+
+    fn context(values: Value) -> Context {
+        Context { values, ..Context::default() }
+    }
+
+    /// Records the generator and the values of every rendered template.
+    #[derive(Default)]
+    struct Recorder {
+        rendered: Vec<(String, Value)>,
+    }
+
+    impl TemplateRenderer for Recorder {
+        fn add_template_dir(&mut self, _dir: &Path) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn render(&mut self, generator: &str, _template: &Path, _input: &str, context: &Value) -> Result<(), String> {
+            self.rendered.push((generator.to_string(), context["values"]["name"].clone()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn user_values_override_parent_values_override_own_values() {
+        let db = generator("db", json!({"host": "localhost", "port": 5432, "user": "app"}), Some("{name: db, version: ^1}"), vec![]);
+        let parent_values = json!({"db": {"host": "db", "port": 5433}});
+        let ctx = context(json!({"db": {"port": 6000}, "host": "ignored"}));
+
+        assert_eq!(db.merged_values(&ctx, Some(&parent_values)), json!({"host": "db", "port": 6000, "user": "app"}));
+    }
+
+    #[test]
+    fn an_alias_is_the_namespace_of_the_values() {
+        let db = generator("db", json!({"port": 5432}), Some("{name: db, version: ^1, alias: cache}"), vec![]);
+        let parent_values = json!({"db": {"port": 1}, "cache": {"port": 6379}});
+        let ctx = context(json!({"db": {"port": 2}}));
+
+        assert_eq!(db.namespace(), "cache");
+        assert_eq!(db.merged_values(&ctx, Some(&parent_values)), json!({"port": 6379}));
+        assert_eq!(db.merged_values(&context(json!({"cache": {"port": 3}})), Some(&parent_values)), json!({"port": 3}));
+    }
+
+    #[test]
+    fn global_values_are_inherited_from_the_parent() {
+        let db = generator("db", json!({"global": {"env": "dev", "region": "eu"}}), Some("{name: db, version: ^1}"), vec![]);
+        let app = generator("app", json!({"global": {"env": "test"}}), None, vec![]);
+        let ctx = context(json!({"global": {"env": "prod"}}));
+
+        let app_values = app.template_values(&ctx, &app.merged_values(&ctx, None), None);
+        assert_eq!(app_values["global"], json!({"env": "prod"}));
+        let db_values = db.template_values(&ctx, &db.merged_values(&ctx, Some(&app_values)), Some(&app_values));
+        assert_eq!(db_values["global"], json!({"env": "prod", "region": "eu"}));
+        let without_globals = generator("web", json!({"port": 80}), None, vec![]);
+        assert_eq!(without_globals.template_values(&Context::default(), &json!({"port": 80}), None), json!({"port": 80, "global": {}}));
+    }
+
+    #[test]
+    fn the_first_resolvable_condition_wins() {
+        let auth = generator("auth", json!({}), Some("{name: auth, version: ^1, condition: 'auth.missing,auth.enabled,docker.enabled', tags: [security]}"), vec![]);
+        let ctx = context(json!({"tags": {"security": true}}));
+
+        assert!(!auth.is_enabled(&ctx, &json!({"auth": {"enabled": false}, "docker": {"enabled": true}})));
+        assert!(auth.is_enabled(&ctx, &json!({"auth": {"enabled": true}})));
+        assert!(!auth.is_enabled(&ctx, &json!({"auth": {"enabled": "no"}, "docker": {"enabled": false}})));
+    }
+
+    #[test]
+    fn tags_decide_when_no_condition_resolves() {
+        let auth = generator("auth", json!({}), Some("{name: auth, version: ^1, condition: auth.enabled, tags: [security, backend]}"), vec![]);
+
+        assert!(auth.is_enabled(&context(json!({"tags": {"security": false, "backend": true}})), &json!({})));
+        assert!(!auth.is_enabled(&context(json!({"tags": {"security": false}})), &json!({"tags": {"backend": false}})));
+        assert!(!auth.is_enabled(&context(json!({"tags": {"security": false}})), &json!({"tags": {"security": true}})));
+        assert!(auth.is_enabled(&Context::default(), &json!({"tags": {"backend": true}})));
+        assert!(auth.is_enabled(&Context::default(), &json!({})));
+    }
+
+    #[test]
+    fn removes_disabled_dependencies_recursively() {
+        let docker = generator("docker", json!({}), Some("{name: docker, version: ^1, condition: docker.enabled}"), vec![]);
+        let auth = generator("auth", json!({"docker": {"enabled": false}}), Some("{name: auth, version: ^1, condition: auth.enabled}"), vec![docker]);
+        let db = generator("db", json!({}), Some("{name: db, version: ^1, condition: db.enabled}"), vec![]);
+        let mut app = generator("app", json!({"auth": {"enabled": true}, "db": {"enabled": false}}), None, vec![auth, db]);
+
+        app.remove_disabled_dependencies(&Context::default(), None);
+
+        let dependencies = app.dependencies.as_ref().unwrap();
+        assert_eq!(dependencies.iter().map(Generator::key).collect::<Vec<_>>(), ["auth:1.0.0"]);
+        assert!(dependencies[0].dependencies.as_ref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn imports_exported_values() {
+        let db = generator("db", json!({"exports": {"connection": {"url": "postgres://db:5432"}}, "port": 5432}),
+            Some("{name: db, version: ^1, import-values: [connection]}"), vec![]);
+        let app = generator("app", json!({"name": "app"}), None, vec![db]);
+
+        assert_eq!(app.merged_values(&Context::default(), None), json!({"name": "app", "url": "postgres://db:5432"}));
+    }
+
+    #[test]
+    fn imports_values_from_child_to_parent_paths() {
+        let declaration = "{name: db, version: ^1, import-values: [{child: port, parent: database.port}, {child: missing, parent: x}]}";
+        let db = generator("db", json!({"port": 5432}), Some(declaration), vec![]);
+        let app = generator("app", json!({"database": {"name": "app"}, "db": {"port": 6000}}), None, vec![db]);
+
+        let values = app.merged_values(&Context::default(), None);
+        assert_eq!(values["database"], json!({"name": "app", "port": 6000}));
+        assert!(values.get("x").is_none());
+    }
+
+    #[test]
+    fn own_values_take_precedence_over_imported_ones() {
+        let db = generator("db", json!({"exports": {"settings": {"port": 5432, "host": "db"}}}),
+            Some("{name: db, version: ^1, import-values: [settings]}"), vec![]);
+        let app = generator("app", json!({"port": 80}), None, vec![db]);
+
+        assert_eq!(app.merged_values(&Context::default(), None), json!({"port": 80, "host": "db"}));
+    }
+
+    #[tokio::test]
+    async fn refuses_directory_sources_when_verifying() {
+        let dir = tempfile::tempdir().unwrap();
+        let dependency = dir.path().join("d");
+        write_generator(&dependency, "d", "", "");
+        let policy = FetchPolicy::new(dir.path(), &UserConfig::default(), true, Keyring::default());
+
+        let error = fetch_template(&dependency.display().to_string(), &policy).await.unwrap_err();
+        assert_eq!(error.exit_code(), 4);
+        let url = Url::parse("file://d").unwrap();
+        let error = fetch(&url, dir.path(), &policy).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn copies_files_to_the_same_relative_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = dir.path().join("files");
+        fs::create_dir_all(files.join("config")).unwrap();
+        fs::write(files.join("config").join("app.toml"), "").unwrap();
+        fs::write(dir.path().join("outside.toml"), "").unwrap();
+        let output = Path::new("out");
+
+        assert_eq!(construct_destination_path(&files, &files.join("config").join("app.toml"), output).unwrap(), output.join("config").join("app.toml"));
+        let error = construct_destination_path(&files, &dir.path().join("outside.toml"), output).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        let error = construct_destination_path(&files, &files.join("missing.toml"), output).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert!(error.to_string().contains("missing.toml"), "{}", error);
+    }
+
+    #[test]
+    fn reuses_an_installed_generator_only_with_the_same_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let repository = dir.path().join("repository");
+        write_generator(&source, "d", "", "name: d\n");
+
+        let installed = install_or_reuse("file://source", &source, None, &repository).unwrap();
+        assert_eq!(installed, repository.join("d").join("1.0.0"));
+        assert_eq!(install_or_reuse("file://source", &source, None, &repository).unwrap(), installed);
+
+        fs::write(source.join("values.yaml"), "name: changed\n").unwrap();
+        let error = install_or_reuse("file://source", &source, None, &repository).unwrap_err();
+        assert_eq!(error.exit_code(), 8);
+        assert!(error.to_string().contains("differs from file://source"), "{}", error);
+        assert_eq!(fs::read_to_string(installed.join("values.yaml")).unwrap(), "name: d\n");
+    }
+
+    #[tokio::test]
+    async fn lists_the_effective_values_of_every_alias() {
+        let dir = tempfile::tempdir().unwrap();
+        let dependencies = "dependencies:\n- name: d\n  version: ^1\n  alias: first\n- name: d\n  version: ^1\n";
+        write_generator(dir.path(), "a", dependencies, "global:\n  env: dev\nfirst:\n  name: one\n");
+        write_generator(&vendored(dir.path(), "d"), "d", "", "name: d\nport: 80\n");
+        let ctx = Context { values: json!({"d": {"port": 8080}}), ..Context::default() };
+
+        let generator = load(dir.path()).await.unwrap();
+        let values = generator.effective_values(&ctx);
+
+        let instances: Vec<&str> = values.iter().map(|(instance, _)| instance.as_str()).collect();
+        assert_eq!(instances, ["d:1.0.0 as first", "d:1.0.0", "a:1.0.0"]);
+        assert_eq!(values[0].1, json!({"name": "one", "port": 80, "global": {"env": "dev"}}));
+        assert_eq!(values[1].1, json!({"name": "d", "port": 8080, "global": {"env": "dev"}}));
+    }
+
+    #[tokio::test]
+    async fn validates_each_generator_against_its_own_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        write_generator(dir.path(), "a", &depends_on(&["d"]), "port: 80\nd:\n  port: http\n");
+        fs::write(dir.path().join("values.schema.json"), r#"{"properties": {"port": {"type": "integer"}}, "additionalProperties": true}"#).unwrap();
+        let d = vendored(dir.path(), "d");
+        write_generator(&d, "d", "", "name: d\n");
+        fs::write(d.join("values.schema.json"), r#"{"properties": {"port": {"type": "integer"}, "name": {"type": "string"}}}"#).unwrap();
+
+        let generator = load(dir.path()).await.unwrap();
+        let violations = generator.validate_values(&Context::default()).unwrap();
+
+        let found: Vec<(&str, &str)> = violations.iter().map(|violation| (violation.generator.as_str(), violation.pointer.as_str())).collect();
+        assert_eq!(found, [("d:1.0.0", "/port")]);
+    }
+
+    #[tokio::test]
+    async fn the_lockfile_pins_the_version_and_content() {
+        let dir = tempfile::tempdir().unwrap();
+        write_generator(dir.path(), "a", &depends_on(&["d"]), "");
+        write_generator(&vendored(dir.path(), "d"), "d", "", "name: d\n");
+        let lock = GeneratorLock::from_generator(&load(dir.path()).await.unwrap()).unwrap();
+        write_lock(dir.path(), &lock).unwrap();
+        let newer = dir.path().join(VENDOR_DIR).join("d").join("1.1.0");
+        write_generator(&newer, "d", "", "name: d\n");
+        fs::write(newer.join("Generator.yaml"), "apiVersion: v1\nname: d\nversion: 1.1.0\n").unwrap();
+
+        let locked = load(dir.path()).await.unwrap();
+        assert_eq!(locked.dependencies.unwrap()[0].generator_yaml.version, "1.0.0");
+
+        fs::write(vendored(dir.path(), "d").join("values.yaml"), "name: changed\n").unwrap();
+        let error = load(dir.path()).await.unwrap_err();
+        assert_eq!(error.exit_code(), 4);
+        assert!(error.to_string().contains("digest of dependency d 1.0.0 does not match Generator.lock"), "{}", error);
+
+        fs::remove_file(dir.path().join(LOCK_FILE)).unwrap();
+        let unlocked = load(dir.path()).await.unwrap();
+        assert_eq!(unlocked.dependencies.unwrap()[0].generator_yaml.version, "1.1.0");
+    }
+
+    #[tokio::test]
+    async fn prefers_the_vendored_copy_over_the_url() {
+        let dir = tempfile::tempdir().unwrap();
+        write_generator(dir.path(), "a", "dependencies:\n- name: d\n  version: ^1\n  url: file://upstream\n", "");
+        write_generator(&dir.path().join("upstream"), "d", "", "name: upstream\n");
+        write_generator(&vendored(dir.path(), "d"), "d", "", "name: vendored\n");
+
+        let generator = load(dir.path()).await.unwrap();
+        assert_eq!(generator.dependencies.as_ref().unwrap()[0].values, json!({"name": "vendored"}));
+
+        fs::remove_dir_all(dir.path().join(VENDOR_DIR)).unwrap();
+        let generator = load(dir.path()).await.unwrap();
+        assert_eq!(generator.dependencies.as_ref().unwrap()[0].values, json!({"name": "upstream"}));
+    }
+
+    #[tokio::test]
+    async fn vendors_dependencies_with_their_own_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("a");
+        write_generator(&source, "a", "dependencies:\n- name: b\n  version: ^1\n  url: file://../b\n", "");
+        write_generator(&dir.path().join("b"), "b", "dependencies:\n- name: d\n  version: ^1\n  url: file://../d\n", "");
+        write_generator(&dir.path().join("d"), "d", "", "");
+
+        load(&source).await.unwrap().vendor_dependencies(&source).unwrap();
+
+        let b = vendored(&source, "b");
+        assert!(b.join("Generator.yaml").is_file());
+        assert!(vendored(&b, "d").join("Generator.yaml").is_file());
+        fs::remove_dir_all(dir.path().join("d")).unwrap();
+        let generator = load(&source).await.unwrap();
+        assert_eq!(generator.dependencies.unwrap()[0].dependencies.as_ref().unwrap()[0].base_path, vendored(&b, "d").display().to_string());
+    }
+
+    #[tokio::test]
+    async fn reports_a_dependency_cycle_with_its_path() {
+        let dir = tempfile::tempdir().unwrap();
+        write_generator(dir.path(), "a", &depends_on(&["b"]), "");
+        let b = vendored(dir.path(), "b");
+        write_generator(&b, "b", &depends_on(&["a"]), "");
+        write_generator(&vendored(&b, "a"), "a", &depends_on(&["b"]), "");
+
+        let error = load(dir.path()).await.unwrap_err();
+
+        assert_eq!(error.exit_code(), 3);
+        assert!(error.to_string().contains("dependency cycle detected: a:1.0.0 -> b:1.0.0 -> a:1.0.0"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn loads_and_renders_a_shared_dependency_once() {
+        let dir = tempfile::tempdir().unwrap();
+        write_generator(dir.path(), "a", &depends_on(&["b", "c"]), "");
+        for parent in ["b", "c"] {
+            let parent_dir = vendored(dir.path(), parent);
+            write_generator(&parent_dir, parent, &depends_on(&["d"]), "");
+            write_generator(&vendored(&parent_dir, "d"), "d", "", "name: d\n");
+        }
+
+        let generator = load(dir.path()).await.unwrap();
+        let shared: Vec<&str> = generator.dependencies.iter().flatten()
+            .map(|dependency| dependency.dependencies.as_ref().unwrap()[0].base_path.as_str())
+            .collect();
+        assert_eq!(shared[0], shared[1]);
+
+        let mut recorder = Recorder::default();
+        generator.generate_templates(&mut recorder, &Context::default()).unwrap();
+        let generators: Vec<&str> = recorder.rendered.iter().map(|(generator, _)| generator.as_str()).collect();
+        assert_eq!(generators, ["d:1.0.0", "b:1.0.0", "c:1.0.0", "a:1.0.0"]);
+    }
+
+    #[tokio::test]
+    async fn renders_each_alias_of_a_dependency_with_its_own_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let dependencies = "dependencies:\n- name: d\n  version: ^1\n  alias: first\n- name: d\n  version: ^1\n  alias: second\n";
+        write_generator(dir.path(), "a", dependencies, "first:\n  name: one\nsecond:\n  name: two\n");
+        write_generator(&vendored(dir.path(), "d"), "d", "", "name: d\n");
+
+        let generator = load(dir.path()).await.unwrap();
+        let mut recorder = Recorder::default();
+        generator.generate_templates(&mut recorder, &Context::default()).unwrap();
+
+        let rendered: Vec<(&str, &Value)> = recorder.rendered.iter().map(|(generator, name)| (generator.as_str(), name)).collect();
+        assert_eq!(rendered, [("d:1.0.0", &json!("one")), ("d:1.0.0", &json!("two")), ("a:1.0.0", &Value::Null)]);
+    }
+}
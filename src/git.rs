@@ -0,0 +1,214 @@
+use std::{fs, io};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use git2::build::{CheckoutBuilder, RepoBuilder};
+use git2::{Cred, CredentialType, FetchOptions, Object, ObjectType, RemoteCallbacks, Repository};
+use tracing::{debug, info};
+
+/// A generator in a git repository, written as `git+<scheme>://<repository>[//<subdirectory>][?ref=<branch or tag>|?rev=<commit>]`,
+/// e.g. `git+https://github.com/acme/platform.git//generators/api?ref=v1.2.0`.
+/// The schemes `https`, `http`, `ssh` and `file` are supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitSource {
+    /// the url git clones, without the `git+` prefix and the selectors
+    pub url: String,
+    /// a branch or tag to check out
+    pub reference: Option<String>,
+    /// a commit to check out
+    pub revision: Option<String>,
+    /// the directory of the generator inside the repository
+    pub subdirectory: Option<PathBuf>,
+}
+
+pub fn is_git_uri(uri: &str) -> bool {
+    uri.starts_with("git+")
+}
+
+impl GitSource {
+    pub fn parse(uri: &str) -> Result<Self, io::Error> {
+        let rest = uri.strip_prefix("git+")
+            .ok_or_else(|| invalid(format!("Git uri {} must start with git+", uri)))?;
+        let (location, query) = match rest.split_once('?') {
+            Some((location, query)) => (location, Some(query)),
+            None => (rest, None),
+        };
+        let scheme_end = location.find("://")
+            .ok_or_else(|| invalid(format!("Git uri {} has no scheme", uri)))?;
+        let scheme = &location[..scheme_end];
+        if !matches!(scheme, "https" | "http" | "ssh" | "file") {
+            return Err(invalid(format!("Unsupported scheme git+{} in {}", scheme, uri)));
+        }
+        let path_start = scheme_end + "://".len();
+        let (url, subdirectory) = match location[path_start..].find("//") {
+            Some(separator) => {
+                let separator = path_start + separator;
+                (&location[..separator], Some(&location[separator + 2..]))
+            }
+            None => (location, None),
+        };
+
+        let mut reference = None;
+        let mut revision = None;
+        for parameter in query.into_iter().flat_map(|query| query.split('&')).filter(|parameter| !parameter.is_empty()) {
+            match parameter.split_once('=') {
+                Some(("ref", value)) if !value.is_empty() => reference = Some(value.to_string()),
+                Some(("rev", value)) if !value.is_empty() => revision = Some(value.to_string()),
+                _ => return Err(invalid(format!("Unsupported parameter '{}' in {}, expected ref=<branch or tag> or rev=<commit>", parameter, uri))),
+            }
+        }
+        if reference.is_some() && revision.is_some() {
+            return Err(invalid(format!("Git uri {} selects both a ref and a rev", uri)));
+        }
+
+        let subdirectory = subdirectory
+            .map(|subdirectory| subdirectory.trim_end_matches('/'))
+            .filter(|subdirectory| !subdirectory.is_empty())
+            .map(PathBuf::from);
+        if let Some(subdirectory) = &subdirectory {
+            if !subdirectory.components().all(|component| matches!(component, Component::Normal(_))) {
+                return Err(invalid(format!("Subdirectory {} in {} must be a relative path inside the repository", subdirectory.display(), uri)));
+            }
+        }
+
+        Ok(GitSource { url: url.to_string(), reference, revision, subdirectory })
+    }
+
+    /// Clones the repository into `destination` and checks out the selected ref or commit, or the default branch.
+    /// A relative `file://` repository is resolved against `base_path`.
+    /// Returns the directory of the generator and the commit that was checked out.
+    pub fn checkout(&self, base_path: &Path, destination: &Path) -> Result<(PathBuf, String), io::Error> {
+        let url = match self.url.strip_prefix("file://") {
+            Some(path) if Path::new(path).is_relative() => base_path.join(path).to_string_lossy().into_owned(),
+            _ => self.url.clone(),
+        };
+        info!("Cloning {} into {}", url, destination.display());
+
+        let mut attempts = 0;
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(move |url, username, allowed| {
+            attempts += 1;
+            if attempts > 3 {
+                return Err(git2::Error::from_str("authentication failed"));
+            }
+            credentials(url, username, allowed)
+        });
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(callbacks);
+        let repository = RepoBuilder::new()
+            .fetch_options(fetch_options)
+            .clone(&url, destination)
+            .map_err(git_error)?;
+
+        let commit = {
+            let object = match (&self.reference, &self.revision) {
+                (Some(reference), _) => resolve_reference(&repository, reference)?,
+                (None, Some(revision)) => repository.revparse_single(revision)
+                    .map_err(|e| io::Error::new(ErrorKind::NotFound, format!("Commit {} not found in {}: {}", revision, url, e.message())))?,
+                (None, None) => repository.head().and_then(|head| head.peel(ObjectType::Commit)).map_err(git_error)?,
+            };
+            let commit = object.peel_to_commit().map_err(git_error)?;
+            repository.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().force())).map_err(git_error)?;
+            repository.set_head_detached(commit.id()).map_err(git_error)?;
+            commit.id().to_string()
+        };
+        debug!("Checked out commit {} of {}", commit, url);
+        drop(repository);
+        // the history is not part of the generator
+        fs::remove_dir_all(destination.join(".git"))?;
+
+        let path = match &self.subdirectory {
+            Some(subdirectory) => destination.join(subdirectory),
+            None => destination.to_path_buf(),
+        };
+        if !path.is_dir() {
+            return Err(io::Error::new(ErrorKind::NotFound, format!("Directory {} does not exist in {} at commit {}",
+                self.subdirectory.as_deref().unwrap_or(Path::new(".")).display(), url, commit)));
+        }
+        Ok((path, commit))
+    }
+}
+
+/// Resolves a tag or a branch, tags first since generators are usually pinned to release tags.
+fn resolve_reference<'r>(repository: &'r Repository, reference: &str) -> Result<Object<'r>, io::Error> {
+    [format!("refs/tags/{}", reference), format!("refs/remotes/origin/{}", reference)].iter()
+        .find_map(|name| repository.revparse_single(name).ok())
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("No branch or tag named {}", reference)))
+}
+
+/// Uses the ssh agent for ssh repositories and the configured git credential helper for https ones.
+fn credentials(url: &str, username: Option<&str>, allowed: CredentialType) -> Result<Cred, git2::Error> {
+    if allowed.contains(CredentialType::SSH_KEY) {
+        return Cred::ssh_key_from_agent(username.unwrap_or("git"));
+    }
+    if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+        let config = git2::Config::open_default()?;
+        return Cred::credential_helper(&config, url, username);
+    }
+    Cred::default()
+}
+
+fn git_error(e: git2::Error) -> io::Error {
+    io::Error::other(e.message().to_string())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(url: &str, reference: Option<&str>, revision: Option<&str>, subdirectory: Option<&str>) -> GitSource {
+        GitSource {
+            url: url.to_string(),
+            reference: reference.map(str::to_string),
+            revision: revision.map(str::to_string),
+            subdirectory: subdirectory.map(PathBuf::from),
+        }
+    }
+
+    #[test]
+    fn parses_a_repository() {
+        assert_eq!(GitSource::parse("git+https://github.com/acme/platform.git").unwrap(),
+            source("https://github.com/acme/platform.git", None, None, None));
+    }
+
+    #[test]
+    fn parses_a_subdirectory_and_a_ref() {
+        assert_eq!(GitSource::parse("git+https://github.com/acme/platform.git//generators/api/?ref=v1.2.0").unwrap(),
+            source("https://github.com/acme/platform.git", Some("v1.2.0"), None, Some("generators/api")));
+    }
+
+    #[test]
+    fn parses_a_rev() {
+        assert_eq!(GitSource::parse("git+http://git.internal/platform?rev=4f2a9c1").unwrap(),
+            source("http://git.internal/platform", None, Some("4f2a9c1"), None));
+    }
+
+    #[test]
+    fn parses_ssh_and_file_repositories() {
+        assert_eq!(GitSource::parse("git+ssh://git@github.com/acme/platform.git//api").unwrap(),
+            source("ssh://git@github.com/acme/platform.git", None, None, Some("api")));
+        assert_eq!(GitSource::parse("git+file:///srv/git/platform//generators/api?ref=main").unwrap(),
+            source("file:///srv/git/platform", Some("main"), None, Some("generators/api")));
+        assert_eq!(GitSource::parse("git+file://platform").unwrap(), source("file://platform", None, None, None));
+    }
+
+    #[test]
+    fn rejects_malformed_uris() {
+        for uri in [
+            "https://github.com/acme/platform.git",
+            "git+github.com/acme/platform.git",
+            "git+ftp://example.com/platform.git",
+            "git+https://github.com/acme/platform.git?branch=main",
+            "git+https://github.com/acme/platform.git?ref=",
+            "git+https://github.com/acme/platform.git?ref=main&rev=4f2a9c1",
+            "git+https://github.com/acme/platform.git//../escape",
+            "git+https://github.com/acme/platform.git///absolute",
+        ] {
+            let error = GitSource::parse(uri).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput, "{}", uri);
+        }
+    }
+}
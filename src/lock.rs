@@ -0,0 +1,213 @@
+use std::{fs, io};
+use std::io::ErrorKind;
+use std::path::Path;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+use crate::generator::{glob_below, Dependency, Generator, VENDOR_DIR};
+use crate::repository::INSTALL_METADATA;
+
+pub const LOCK_FILE: &str = "Generator.lock";
+
+/// Content of `Generator.lock`, the exact dependency tree resolved by `protypo dependency update`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GeneratorLock {
+    /// digest of the dependencies declared in `Generator.yaml`, used to detect an outdated lockfile
+    #[serde(rename = "digest")]
+    pub digest: String,
+
+    #[serde(rename = "dependencies", default)]
+    pub dependencies: Vec<LockedDependency>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LockedDependency {
+    #[serde(rename = "name")]
+    pub name: String,
+
+    #[serde(rename = "alias", skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+
+    /// the exact version that was resolved
+    #[serde(rename = "version")]
+    pub version: String,
+
+    /// where the dependency was fetched from, missing for dependencies resolved from the local repository
+    #[serde(rename = "url", skip_serializing_if = "Option::is_none")]
+    pub url: Option<Url>,
+
+    /// digest of the content of the dependency, see [`directory_digest`]
+    #[serde(rename = "digest")]
+    pub digest: String,
+
+    #[serde(rename = "dependencies", default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<LockedDependency>,
+}
+
+impl GeneratorLock {
+    /// Builds the lock of a freshly resolved generator tree.
+    pub fn from_generator(generator: &Generator) -> Result<Self, io::Error> {
+        Ok(GeneratorLock {
+            digest: dependencies_digest(generator.generator_yaml.dependencies.as_deref().unwrap_or_default()),
+            dependencies: locked_dependencies(generator)?,
+        })
+    }
+
+    /// Whether the lock was created from the dependencies currently declared in `Generator.yaml`.
+    pub fn is_up_to_date(&self, dependencies: &[Dependency]) -> bool {
+        self.digest == dependencies_digest(dependencies)
+    }
+}
+
+impl LockedDependency {
+    /// Whether this entry locks the given dependency declaration.
+    pub fn locks(&self, dependency: &Dependency) -> bool {
+        self.name == dependency.name && self.alias == dependency.alias
+    }
+}
+
+fn locked_dependencies(generator: &Generator) -> Result<Vec<LockedDependency>, io::Error> {
+    generator.dependencies.iter()
+        .flatten()
+        .map(|dependency| {
+            let declaration = dependency.dependency.as_ref();
+            Ok(LockedDependency {
+                name: dependency.generator_yaml.name.clone(),
+                alias: declaration.and_then(|declaration| declaration.alias.clone()),
+                version: dependency.generator_yaml.version.clone(),
+                url: declaration.and_then(|declaration| declaration.repository.clone()),
+                digest: directory_digest(Path::new(&dependency.base_path))?,
+                dependencies: locked_dependencies(dependency)?,
+            })
+        })
+        .collect()
+}
+
+pub fn read_lock(base_path: &Path) -> Result<Option<GeneratorLock>, io::Error> {
+    let lock_path = base_path.join(LOCK_FILE);
+    if !lock_path.is_file() {
+        return Ok(None);
+    }
+    debug!("Reading lockfile {}", lock_path.display());
+    let content = fs::read_to_string(&lock_path)?;
+    let lock = serde_yaml::from_str(&content)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("Cannot deserialize file {:?} due to error:{:?}", lock_path, e)))?;
+    Ok(Some(lock))
+}
+
+pub fn write_lock(base_path: &Path, lock: &GeneratorLock) -> Result<(), io::Error> {
+    let content = serde_yaml::to_string(lock)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    fs::write(base_path.join(LOCK_FILE), content)
+}
+
+fn dependencies_digest(dependencies: &[Dependency]) -> String {
+    bytes_digest(&serde_json::to_vec(dependencies).unwrap_or_default())
+}
+
+/// Digest of a file such as a packaged generator, in the same `sha256:<hex>` form as the lockfile.
+pub fn bytes_digest(bytes: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(bytes)))
+}
+
+/// Digest over the relative paths and contents of every file below `path`, in a stable order,
+/// so the same generator gives the same digest wherever it is extracted.
+/// Vendored dependencies are left out, they are verified by their own entries in the lockfile,
+/// and so is the install metadata the local repository adds to a generator.
+pub fn directory_digest(path: &Path) -> Result<String, io::Error> {
+    let mut files: Vec<(String, std::path::PathBuf)> = glob_below(path, "**/*")?
+        .filter_map(|entry| entry.ok())
+        .filter(|file| file.is_file())
+        .filter_map(|file| {
+            let relative = file.strip_prefix(path).ok()?;
+            if relative.starts_with(VENDOR_DIR) || relative == Path::new(INSTALL_METADATA) {
+                return None;
+            }
+            let relative = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+                .join("/");
+            Some((relative, file))
+        })
+        .collect();
+    files.sort();
+
+    let mut hasher = Sha256::new();
+    for (relative, file) in files {
+        let content = fs::read(&file)?;
+        hasher.update(relative.as_bytes());
+        hasher.update([0]);
+        hasher.update((content.len() as u64).to_le_bytes());
+        hasher.update(&content);
+    }
+    Ok(format!("sha256:{}", hex::encode(hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dependencies(yaml: &str) -> Vec<Dependency> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn directory_digest_covers_paths_and_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("templates")).unwrap();
+        fs::write(dir.path().join("Generator.yaml"), "name: a\n").unwrap();
+        fs::write(dir.path().join("templates").join("a.t"), "a").unwrap();
+        let digest = directory_digest(dir.path()).unwrap();
+        assert!(digest.starts_with("sha256:"));
+
+        fs::write(dir.path().join("templates").join("a.t"), "b").unwrap();
+        let changed = directory_digest(dir.path()).unwrap();
+        assert_ne!(changed, digest);
+        fs::rename(dir.path().join("templates").join("a.t"), dir.path().join("templates").join("b.t")).unwrap();
+        assert_ne!(directory_digest(dir.path()).unwrap(), changed);
+    }
+
+    #[test]
+    fn directory_digest_ignores_vendored_generators_and_install_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("Generator.yaml"), "name: a\n").unwrap();
+        let digest = directory_digest(dir.path()).unwrap();
+
+        fs::create_dir_all(dir.path().join(VENDOR_DIR).join("d").join("1.0.0")).unwrap();
+        fs::write(dir.path().join(VENDOR_DIR).join("d").join("1.0.0").join("Generator.yaml"), "name: d\n").unwrap();
+        fs::write(dir.path().join(INSTALL_METADATA), "source: file://a\n").unwrap();
+        assert_eq!(directory_digest(dir.path()).unwrap(), digest);
+    }
+
+    #[test]
+    fn detects_an_outdated_lock() {
+        let declared = dependencies("- name: d\n  version: ^1\n");
+        let lock = GeneratorLock { digest: dependencies_digest(&declared), dependencies: vec![] };
+
+        assert!(lock.is_up_to_date(&declared));
+        assert!(!lock.is_up_to_date(&dependencies("- name: d\n  version: ^2\n")));
+    }
+
+    #[test]
+    fn locks_dependencies_by_name_and_alias() {
+        let locked: LockedDependency = serde_yaml::from_str("name: d\nalias: cache\nversion: 1.0.0\ndigest: sha256:00\n").unwrap();
+        let declared = dependencies("- name: d\n  version: ^1\n  alias: cache\n- name: d\n  version: ^1\n");
+
+        assert!(locked.locks(&declared[0]));
+        assert!(!locked.locks(&declared[1]));
+    }
+
+    #[test]
+    fn round_trips_through_the_lockfile() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read_lock(dir.path()).unwrap().is_none());
+        let lock: GeneratorLock = serde_yaml::from_str("digest: sha256:01\ndependencies:\n- name: d\n  version: 1.0.0\n  url: https://example.com/d-1.0.0.tar.gz\n  digest: sha256:02\n").unwrap();
+
+        write_lock(dir.path(), &lock).unwrap();
+        let read = read_lock(dir.path()).unwrap().unwrap();
+        assert_eq!(read.digest, "sha256:01");
+        assert_eq!(read.dependencies[0].url.as_ref().map(Url::as_str), Some("https://example.com/d-1.0.0.tar.gz"));
+    }
+}
@@ -1,17 +1,32 @@
+mod archive;
+mod cache;
+mod config;
+mod conflict;
+mod error;
 mod generator;
+mod git;
+mod lock;
+mod package;
+mod plan;
+mod remote;
+mod repository;
+mod signing;
+mod trust;
+mod values;
 
 use std::{fs, io};
 use std::fs::File;
-use std::io::copy;
+use std::io::{copy, IsTerminal};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use anyhow::{anyhow, Error};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use clap_derive::Subcommand;
 use futures::future::err;
-use jsonptr::Pointer;
+use json_value_merge::Merge;
 use reqwest::Url;
 use rrgen::RRgen;
-use semver::Version;
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use tokio::io::AsyncBufReadExt;
@@ -19,7 +34,18 @@ use tracing::{debug, error, info};
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::fmt::format;
 use zip::ZipArchive;
-use crate::generator::{dereference_config, install_template, Generator};
+use crate::cache::{cache_entries, clear, prune};
+use crate::config::{read_user_config, FetchPolicy};
+use crate::conflict::{resolve_conflicts, ConflictPolicy, ConflictStrategy};
+use crate::error::ProtypoError;
+use crate::generator::{dereference_config, fetch_template, install_or_reuse, install_template, read_optional_entities, Generator, GeneratorYaml, VENDOR_DIR};
+use crate::lock::{read_lock, write_lock, GeneratorLock, LockedDependency, LOCK_FILE};
+use crate::package::package;
+use crate::plan::{Action, Change, Plan, Planner};
+use crate::remote::{build_index, download_generator, parse_repository_url, read_repositories, remove_cached_index, resolve_remote, update_index, validate_repository_name, write_index, write_repositories, RemoteRepository, INDEX_FILE};
+use crate::repository::{installed_generators, installed_versions, matches_search, parse_version_req, resolve_installed, split_generator_ref, uninstall, InstalledGenerator};
+use crate::signing::{generate_key, read_keyring, sign_package, write_keyring};
+use crate::values::{apply_set, layer_values, ListMerge, SchemaViolation, SetKind};
 
 /// A fictional versioning CLI
 #[derive(Parser, Debug)]
@@ -27,6 +53,9 @@ use crate::generator::{dereference_config, install_template, Generator};
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// only use the download cache, failing instead of fetching generators from the network
+    #[arg(long, global = true)]
+    offline: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -45,6 +74,7 @@ pub struct Dependency {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 pub struct Context {
     values: Value,
     entities: Value,
@@ -63,14 +93,21 @@ impl Default for Context {
 
 
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 pub struct Generate {
     output: String,
+    /// how lists of user values are merged into the values of a generator
+    list_merge: ListMerge,
+    /// what happens to output files that already exist with other content
+    on_conflict: ConflictStrategy,
 }
 
 impl Default for Generate {
     fn default() -> Generate {
         Generate{
             output: ".".to_string(),
+            list_merge: ListMerge::default(),
+            on_conflict: ConflictStrategy::default(),
         }
     }
 }
@@ -79,7 +116,33 @@ enum Commands {
     /// install template to local repo
     Install {
         /// uri of the template to install
-        url: String
+        url: String,
+        /// replace the generator if the same version is already installed
+        #[arg(long)]
+        force: bool,
+        /// only install packages signed by a key of the keyring
+        #[arg(long)]
+        verify: bool,
+    },
+    /// list the generators installed in the local repository
+    List,
+    /// show a file of an installed generator, e.g. `protypo show api@^1.2 values`
+    Show {
+        /// name of the generator, optionally with a version constraint as `name@version`. Defaults to the latest version
+        generator: String,
+        /// what to show
+        #[arg(value_enum, default_value_t = ShowField::Generator)]
+        field: ShowField,
+    },
+    /// remove a generator from the local repository, all of its versions unless one is given as `name@version`
+    Uninstall {
+        /// name of the generator, optionally with a version as `name@version`
+        generator: String,
+    },
+    /// search the installed generators by name, keywords, description and maintainers
+    Search {
+        /// the term to search for, ignoring case
+        term: String,
     },
     /// create a new template scaffold
     New {
@@ -88,7 +151,8 @@ enum Commands {
     },
     /// use generator to create output
     Generate {
-        /// path to config file
+        /// path to a YAML or JSON config file with `values`, `entities` and `generate` sections.
+        /// Values passed with `--values` and `--set` take precedence over the ones in the config file
         #[arg(short='c',long)]
         config_filepath: Option<String>,
 
@@ -98,22 +162,231 @@ enum Commands {
 
         #[arg(short='o',long)]
         output_directory: Option<PathBuf>,
-        /// the name of the generator
+        /// the name of the generator, looked up in the local repository and then in the remote repositories.
+        /// Use `<repository>/<name>` to only look in one remote repository
         #[arg(short, long, conflicts_with = "uri")]
         name: Option<String>,
-        /// the name of the generator
-        #[arg(short, long, conflicts_with = "uri")]
+        /// version constraint of the generator, e.g. `1.2.3`, `^1.2` or `>=1, <2`. Defaults to the latest installed version
+        #[arg(short, long, conflicts_with = "uri", requires = "name")]
         version: Option<String>,
         /// uri to download and use generator
         #[arg(short='u', long, conflicts_with = "name", conflicts_with = "version")]
         uri: Option<String>,
+        /// YAML or JSON file with values, can be given multiple times and later files take precedence.
+        /// A `null` value deletes the key from the values of the generator
+        #[arg(short='f', long = "values")]
+        values_files: Vec<PathBuf>,
+        /// how lists are merged when values are overridden, defaults to `replace`
+        #[arg(long, value_enum)]
+        list_merge: Option<ListMerge>,
+        /// set values on the command line, e.g. `--set app.port=8080,app.tags={a,b},list[0].name=x`
         #[arg(long = "set")]
         sets: Vec<String>,
-    }
+        /// set string values on the command line, values are never converted to other types
+        #[arg(long = "set-string")]
+        set_strings: Vec<String>,
+        /// set a JSON value on the command line, e.g. `--set-json 'app.ports=[80,443]'`
+        #[arg(long = "set-json")]
+        set_jsons: Vec<String>,
+        /// set a value from the content of a file, e.g. `--set-file app.banner=banner.txt`
+        #[arg(long = "set-file")]
+        set_files: Vec<String>,
+        /// only use remote generators and dependencies that are packages signed by a key of the keyring
+        #[arg(long)]
+        verify: bool,
+        /// render everything into memory and print what would be written, without touching the output
+        #[arg(long)]
+        dry_run: bool,
+        /// format of the plan printed by `--dry-run`
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text, requires = "dry_run")]
+        output_format: OutputFormat,
+        /// render everything into memory and print a unified diff against the current output, without writing it
+        #[arg(long, conflicts_with = "dry_run")]
+        diff: bool,
+        /// when to color the diff
+        #[arg(long, value_enum, default_value_t = DiffColor::Auto, requires = "diff")]
+        color: DiffColor,
+        /// exit with an error when generating would change any file, without writing it
+        #[arg(long, conflicts_with = "dry_run")]
+        check: bool,
+        /// what happens to output files that already exist with other content, defaults to `overwrite`.
+        /// Templates override it with `on_conflict` in their frontmatter, generators with `conflicts` rules in their Generator.yaml
+        #[arg(long, value_enum)]
+        on_conflict: Option<ConflictStrategy>,
+        /// print the values every generator renders its templates with, after defaults, overrides and imports, without generating
+        #[arg(long, conflicts_with_all = ["dry_run", "diff", "check"])]
+        show_values: bool,
+    },
+    /// validate a generator and write it as a `<name>-<version>.tar.gz` archive with a `.sha256` digest file.
+    /// Files matching the patterns in `.protypoignore` are left out
+    Package {
+        /// path to the generator
+        #[arg(default_value = ".")]
+        path: PathBuf,
+        /// directory to write the archive to
+        #[arg(short, long, default_value = ".")]
+        destination: PathBuf,
+        /// sign the package with a key created with `protypo key generate`, writing a `.prov` provenance file next to it
+        #[arg(long)]
+        sign: Option<String>,
+    },
+    /// manage the dependencies of a generator
+    Dependency {
+        #[command(subcommand)]
+        command: DependencyCommands,
+    },
+    /// manage the remote repositories generators are resolved from by name
+    Repo {
+        #[command(subcommand)]
+        command: RepoCommands,
+    },
+    /// manage the keys generator packages are signed with and verified against
+    Key {
+        #[command(subcommand)]
+        command: KeyCommands,
+    },
+    /// manage the cache of downloaded generators
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommands {
+    /// list the cached downloads, the most recently used first
+    List,
+    /// remove the downloads that were not used for a while
+    Prune {
+        /// remove the downloads not used for this many days
+        #[arg(long, default_value_t = 30)]
+        older_than: u64,
+    },
+    /// remove every cached download
+    Clear,
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyCommands {
+    /// create a signing key and add its public key to the keyring
+    Generate {
+        /// name of the key
+        name: String,
+    },
+    /// trust the public key of a publisher
+    Add {
+        /// name to refer to the key
+        name: String,
+        /// hex encoded ed25519 public key
+        public_key: String,
+    },
+    /// list the keys of the keyring
+    List,
+    /// stop trusting a key
+    Remove {
+        /// name of the key
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum RepoCommands {
+    /// add a repository, the url of a static file server or a local directory with an index.yaml
+    Add {
+        /// name to refer to the repository, e.g. in `repo://<name>/<generator>` dependency urls
+        name: String,
+        /// url of the repository
+        url: String,
+    },
+    /// list the added repositories
+    List,
+    /// remove a repository and its cached index
+    Remove {
+        /// name of the repository
+        name: String,
+    },
+    /// download the index of every repository again, or only of the given ones
+    Update {
+        /// names of the repositories
+        names: Vec<String>,
+    },
+    /// write an index.yaml for the packaged generators in a directory
+    Index {
+        /// directory with the generator archives
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+        /// url the directory will be served from, by default the urls in the index are relative to it
+        #[arg(long)]
+        url: Option<String>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    /// a table for reading
+    Text,
+    /// JSON for tooling
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DiffColor {
+    /// when printing to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ShowField {
+    /// Generator.yaml
+    Generator,
+    /// values.yaml
+    Values,
+    /// values.schema.json
+    Schema,
+    /// README.md
+    Readme,
+    /// the entity schemas in the entities directory
+    Entities,
+}
+
+#[derive(Subcommand, Debug)]
+enum DependencyCommands {
+    /// resolve the dependencies of a generator again and write them to Generator.lock
+    Update {
+        /// path to the generator
+        #[arg(default_value = ".")]
+        path: PathBuf,
+    },
+    /// copy the dependencies, as pinned by Generator.lock if present, into the generators directory of the generator
+    Build {
+        /// path to the generator
+        #[arg(default_value = ".")]
+        path: PathBuf,
+    },
+    /// list the dependencies of a generator with their locked and vendored versions
+    List {
+        /// path to the generator
+        #[arg(default_value = ".")]
+        path: PathBuf,
+    },
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Error> {
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {}", e);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+/// Exit code of the process when it fails with `error`, the one of the [`ProtypoError`] if it is one.
+fn exit_code(error: &Error) -> i32 {
+    error.downcast_ref::<ProtypoError>().map(ProtypoError::exit_code).unwrap_or(1)
+}
+
+async fn run() -> Result<(), Error> {
     let mut rrgen = RRgen::default();
     rrgen.document_separator = "---\n".to_string();
     rrgen.frontmatter_separator = "===\n".to_string();
@@ -128,18 +401,200 @@ async fn main() -> Result<(), Error> {
     let local_repo_generators = local_repo.join("generators");
     info!("directory for installing templates: {:?}!", local_repo_generators);
     match &cli.command {
-        Commands::Install { url } => {
+        Commands::Install { url, force, verify } => {
             info!("dir to install templates: {:?}!", local_repo_generators);
-            install_template(&url, &local_repo_generators);
+            let policy = fetch_policy(&local_repo, *verify, cli.offline)?;
+            let (generator, path) = install_template(url, &local_repo_generators, *force, &policy).await?;
+            println!("Installed {} {} into {}", generator.name, generator.version, path.display());
+            Ok(())
+        },
+        Commands::List => {
+            print_generators(&installed_generators(&local_repo_generators));
+            Ok(())
+        },
+        Commands::Search { term } => {
+            let found: Vec<InstalledGenerator> = installed_generators(&local_repo_generators).into_iter()
+                .filter(|installed| matches_search(&installed.generator_yaml, term))
+                .collect();
+            print_generators(&found);
             Ok(())
         },
+        Commands::Show { generator, field } => {
+            let (name, version) = split_generator_ref(generator);
+            let requirement = version.map(parse_version_req).transpose()?;
+            let (_, path) = resolve_installed(&local_repo_generators, name, requirement.as_ref())?;
+            show_generator(&path, *field)
+        },
+        Commands::Uninstall { generator } => {
+            let (name, version) = split_generator_ref(generator);
+            let version = version.map(Version::parse).transpose()?;
+            for removed in uninstall(&local_repo_generators, name, version.as_ref())? {
+                println!("Uninstalled {} {}", name, removed);
+            }
+            Ok(())
+        },
+        Commands::Package { path, destination, sign } => {
+            let (generator, archive_path, digest) = package(path, destination)?;
+            println!("Packaged {} {} into {} ({})", generator.name, generator.version, archive_path.display(), digest);
+            if let Some(key_name) = sign {
+                let provenance_path = sign_package(&local_repo, key_name, &archive_path, &generator)?;
+                println!("Signed with key {} into {}", key_name, provenance_path.display());
+            }
+            Ok(())
+        },
+        Commands::Cache { command } => match command {
+            CacheCommands::List => {
+                let entries = cache_entries(&local_repo)?;
+                if entries.is_empty() {
+                    println!("No cached downloads");
+                    return Ok(());
+                }
+                println!("{:<60} {:<20} {:>10} LAST USED", "URL", "DIGEST", "SIZE");
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                for entry in &entries {
+                    let days = now.saturating_sub(entry.last_used) / (24 * 60 * 60);
+                    println!("{:<60} {:<20.20} {:>10} {} day(s) ago", entry.url, entry.digest, entry.size, days);
+                }
+                Ok(())
+            }
+            CacheCommands::Prune { older_than } => {
+                let freed = prune(&local_repo, Duration::from_secs(older_than * 24 * 60 * 60))?;
+                println!("Pruned the download cache, freed {} bytes", freed);
+                Ok(())
+            }
+            CacheCommands::Clear => {
+                let freed = clear(&local_repo)?;
+                println!("Cleared the download cache, freed {} bytes", freed);
+                Ok(())
+            }
+        },
+        Commands::Key { command } => match command {
+            KeyCommands::Generate { name } => {
+                let public_key = generate_key(&local_repo, name)?;
+                println!("Created key {} with public key {}", name, public_key);
+                Ok(())
+            }
+            KeyCommands::Add { name, public_key } => {
+                let mut keyring = read_keyring(&local_repo)?;
+                keyring.add(name, public_key)?;
+                write_keyring(&local_repo, &keyring)?;
+                println!("Added key {}", name);
+                Ok(())
+            }
+            KeyCommands::List => {
+                let keyring = read_keyring(&local_repo)?;
+                if keyring.keys.is_empty() {
+                    println!("No keys added");
+                    return Ok(());
+                }
+                println!("{:<20} PUBLIC KEY", "NAME");
+                for key in &keyring.keys {
+                    println!("{:<20} {}", key.name, key.public_key);
+                }
+                Ok(())
+            }
+            KeyCommands::Remove { name } => {
+                let mut keyring = read_keyring(&local_repo)?;
+                let count = keyring.keys.len();
+                keyring.keys.retain(|key| key.name != *name);
+                if keyring.keys.len() == count {
+                    return Err(anyhow!("Key {} does not exist", name));
+                }
+                write_keyring(&local_repo, &keyring)?;
+                println!("Removed key {}", name);
+                Ok(())
+            }
+        },
+        Commands::Repo { command } => match command {
+            RepoCommands::Add { name, url } => {
+                validate_repository_name(name)?;
+                let mut repositories = read_repositories(&local_repo)?;
+                if repositories.repositories.iter().any(|repository| repository.name == *name) {
+                    return Err(anyhow!("Repository {} already exists", name));
+                }
+                let repository = RemoteRepository { name: name.clone(), url: parse_repository_url(url)? };
+                let index = update_index(&fetch_policy(&local_repo, false, cli.offline)?, &repository).await?;
+                println!("Added repository {} with {} generator(s)", name, index.entries.len());
+                repositories.repositories.push(repository);
+                write_repositories(&local_repo, &repositories)?;
+                Ok(())
+            }
+            RepoCommands::List => {
+                let repositories = read_repositories(&local_repo)?;
+                if repositories.repositories.is_empty() {
+                    println!("No repositories added");
+                    return Ok(());
+                }
+                println!("{:<20} URL", "NAME");
+                for repository in &repositories.repositories {
+                    println!("{:<20} {}", repository.name, repository.url);
+                }
+                Ok(())
+            }
+            RepoCommands::Remove { name } => {
+                let mut repositories = read_repositories(&local_repo)?;
+                let count = repositories.repositories.len();
+                repositories.repositories.retain(|repository| repository.name != *name);
+                if repositories.repositories.len() == count {
+                    return Err(anyhow!("Repository {} does not exist", name));
+                }
+                write_repositories(&local_repo, &repositories)?;
+                remove_cached_index(&local_repo, name)?;
+                println!("Removed repository {}", name);
+                Ok(())
+            }
+            RepoCommands::Update { names } => {
+                let repositories = read_repositories(&local_repo)?;
+                if let Some(unknown) = names.iter().find(|name| !repositories.repositories.iter().any(|repository| repository.name == **name)) {
+                    return Err(anyhow!("Repository {} does not exist", unknown));
+                }
+                let policy = fetch_policy(&local_repo, false, cli.offline)?;
+                for repository in repositories.repositories.iter().filter(|repository| names.is_empty() || names.contains(&repository.name)) {
+                    let index = update_index(&policy, repository).await?;
+                    println!("Updated repository {} with {} generator(s)", repository.name, index.entries.len());
+                }
+                Ok(())
+            }
+            RepoCommands::Index { dir, url } => {
+                let base_url = url.as_deref().map(Url::parse).transpose()?;
+                let index = build_index(dir, base_url.as_ref())?;
+                write_index(dir, &index)?;
+                let versions: usize = index.entries.values().map(Vec::len).sum();
+                println!("Wrote {} with {} version(s) of {} generator(s)", dir.join(INDEX_FILE).display(), versions, index.entries.len());
+                Ok(())
+            }
+        },
+        Commands::Dependency { command } => match command {
+            DependencyCommands::Update { path } => {
+                let generator = Generator::from_directory_unlocked(path, &fetch_policy(&local_repo, false, cli.offline)?).await?;
+                generator.check_version_constraints()?;
+                let lock = GeneratorLock::from_generator(&generator)?;
+                write_lock(path, &lock)?;
+                print_locked_dependencies(&lock.dependencies, 0);
+                println!("Wrote {}", path.join(LOCK_FILE).display());
+                Ok(())
+            }
+            DependencyCommands::Build { path } => {
+                let generator = Generator::from_directory(path, &fetch_policy(&local_repo, false, cli.offline)?).await?;
+                generator.check_version_constraints()?;
+                generator.vendor_dependencies(path)?;
+                println!("Vendored dependencies of {} into {}", generator.generator_yaml.name, path.join(VENDOR_DIR).display());
+                Ok(())
+            }
+            DependencyCommands::List { path } => {
+                list_dependencies(path)
+            }
+        },
         Commands::New { name } => {
             info!("Creating new template: {name}");
             create_new_template(name);
             Ok(())
         },
-        Commands::Generate { name,version,uri,config_filepath , output_directory, generator_path, sets} => {
-            let mut ctx = Context::default();
+        Commands::Generate { name,version,uri,config_filepath , output_directory, generator_path, values_files, list_merge, sets, set_strings, set_jsons, set_files, verify, dry_run, output_format, diff, color, check, on_conflict, show_values} => {
+            let mut ctx = match config_filepath {
+                Some(config_filepath) => load_context(Path::new(config_filepath))?,
+                None => Context::default(),
+            };
             let output = match output_directory {
                 Some(out) => {
                     if !out.exists() && out.is_dir() {
@@ -154,27 +609,34 @@ async fn main() -> Result<(), Error> {
                 None => {},
             };
 
-            let mut values = json!({});
-            for set in sets {
-                let parts: Vec<&str> = set.splitn(2, '=').collect();
-                if parts.len() != 2 {
-                    return Err(anyhow!("Invalid format for --set. Expected key=value."));
-                }
-
-                let key = parts[0];
-                let val = parts[1];
+            if let Some(list_merge) = list_merge {
+                ctx.generate.list_merge = *list_merge;
+            }
+            if let Some(on_conflict) = on_conflict {
+                ctx.generate.on_conflict = *on_conflict;
+            }
+            layer_values_files(&mut ctx.values, values_files, ctx.generate.list_merge)?;
 
-                let ptr_path= key.replace(".","/");
-                let ptr = Pointer::parse(ptr_path.as_str()).unwrap();
-                let replaced = ptr.assign(&mut values, json!(val)).unwrap().unwrap();
-                values = replaced;
+            // same precedence as helm: --set-json, then --set, --set-string and finally --set-file
+            for set in set_jsons {
+                apply_set(&mut ctx.values, set, SetKind::Json)?;
+            }
+            for set in sets {
+                apply_set(&mut ctx.values, set, SetKind::Typed)?;
+            }
+            for set in set_strings {
+                apply_set(&mut ctx.values, set, SetKind::String)?;
+            }
+            for set in set_files {
+                apply_set(&mut ctx.values, set, SetKind::File)?;
             }
 
+            let policy = fetch_policy(&local_repo, *verify, cli.offline)?;
             let path = match true {
-                true if name.is_some() && version.is_some() => {
+                true if name.is_some() => {
                     let generator_name = name.clone().unwrap();
-                    let generator_version = version.clone().unwrap();
-                    local_repo.join("generators").join(generator_name).join(generator_version)
+                    let requirement = version.as_deref().map(parse_version_req).transpose()?;
+                    resolve_generator(&local_repo, &generator_name, requirement.as_ref(), &policy).await?
                 },
                 true if generator_path.is_some() => {
                     let path = generator_path.clone().unwrap();
@@ -183,23 +645,74 @@ async fn main() -> Result<(), Error> {
                 }
                 true if uri.is_some() => {
                     let uri = uri.clone().unwrap();
-                    debug!("Installing template from URI: {}", uri);
-                    install_template(&uri, &local_repo_generators).await;
-                    PathBuf::new()
+                    let (fetched, commit) = fetch_template(&uri, &policy).await?;
+                    if *dry_run || *diff || *check {
+                        // only rendering into memory leaves the local repository alone as well
+                        fetched
+                    } else {
+                        debug!("Installing template from URI: {}", uri);
+                        install_or_reuse(&uri, &fetched, commit, &local_repo_generators)?
+                    }
                 }
                 _ => {
-                    let error_message = "Error: Either generator name and version or a URI must be provided.";
+                    let error_message = "Error: Either a generator name, a generator path or a URI must be provided.";
                     error!(error_message);
                     return Err(anyhow!(error_message));
                 }
             };
-            let generator = Generator::from_directory(path.as_path()).await?;
+            let mut generator = Generator::from_directory(path.as_path(), &policy).await?;
+            generator.check_version_constraints()?;
+            generator.remove_disabled_dependencies(&ctx, None);
+            if *show_values {
+                for (instance, values) in generator.effective_values(&ctx) {
+                    println!("---\n# {}", instance);
+                    print!("{}", serde_yaml::to_string(&values)?);
+                }
+                return Ok(());
+            }
 
-            generator.copy_files(&Path::new(ctx.generate.output.as_str()).to_path_buf())?;
-            println!("Loaded generator {}",generator.generator_yaml.name);
-            let entities = generator.collect_entities();
+            check_violations(&generator, &generator.validate_values(&ctx)?)?;
+
+            let mut entities = generator.collect_entities();
+            entities.merge(&ctx.entities);
             ctx.entities = entities;
-            generator.generate_templates(&mut rrgen,&ctx)?;
+            // everything is rendered into memory first, so that conflicts with existing files are resolved before rrgen writes anything
+            let conflicts = ConflictPolicy {
+                default: ctx.generate.on_conflict,
+                output_dir: PathBuf::from(&ctx.generate.output),
+                rules: generator.conflict_rules()?,
+            };
+            let mut planner = Planner::new(&rrgen.document_separator, &rrgen.frontmatter_separator, conflicts);
+            generator.plan_files(Path::new(ctx.generate.output.as_str()), &mut planner)?;
+            generator.generate_templates(&mut planner, &ctx)?;
+            let plan = planner.into_plan();
+            if *dry_run || *diff || *check {
+                if *dry_run {
+                    return print_plan(&plan, *output_format);
+                }
+                let changes = plan.changes();
+                if *diff {
+                    let color = match color {
+                        DiffColor::Auto => std::io::stdout().is_terminal(),
+                        DiffColor::Always => true,
+                        DiffColor::Never => false,
+                    };
+                    for change in &changes {
+                        print!("{}", change.unified_diff(color));
+                    }
+                }
+                if *check {
+                    check_changes(&changes)?;
+                }
+                return Ok(());
+            }
+
+            let resolutions = resolve_conflicts(&plan)?;
+            resolutions.back_up()?;
+            generator.copy_files(&Path::new(ctx.generate.output.as_str()).to_path_buf(), &resolutions)?;
+            println!("Loaded generator {}",generator.generator_yaml.name);
+            let mut rrgen = resolutions.keep_skipped(rrgen);
+            generator.generate_templates(&mut rrgen, &ctx)?;
 
             Ok(())
         },
@@ -211,11 +724,187 @@ fn path_to_json(path: &PathBuf) -> Result<Value, Error> {
     fs::read_to_string(path)
         .map_err(|e| anyhow!("invalid config file path: {}", e)) // Handle file reading errors
         .and_then(|content| {
-            serde_json::from_str(&content)
-                .map_err(|e| anyhow!("config file is an invalid JSON: {}", e)) // Handle JSON parsing errors
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("yaml") | Some("yml") => serde_yaml::from_str(&content)
+                    .map_err(|e| anyhow!("config file is an invalid YAML: {}", e)),
+                _ => serde_json::from_str(&content)
+                    .map_err(|e| anyhow!("config file is an invalid JSON: {}", e)), // Handle JSON parsing errors
+            }
         })
 }
 
+/// Layers the `--values` files over the values of the config file, later files taking precedence.
+fn layer_values_files(values: &mut Value, values_files: &[PathBuf], list_merge: ListMerge) -> Result<(), Error> {
+    for values_file in values_files {
+        let file_values = path_to_json(values_file)
+            .map_err(|e| anyhow!("invalid values file {}: {}", values_file.display(), e))?;
+        layer_values(values, &file_values, list_merge);
+    }
+    Ok(())
+}
+
+/// Loads the generation context from a YAML or JSON config file.
+/// Entities referenced with `$ref` are resolved relative to the directory of the config file.
+fn load_context(config_filepath: &Path) -> Result<Context, Error> {
+    debug!("Loading config file: {}", config_filepath.display());
+    let mut config = path_to_json(&config_filepath.to_path_buf())?;
+    if !config.is_object() {
+        return Err(anyhow!("config file {} must contain a mapping", config_filepath.display()));
+    }
+    if config.get("entities").is_some_and(Value::is_object) {
+        let parent_path = config_filepath.parent().unwrap_or(Path::new("."));
+        dereference_config(&mut config, parent_path)
+            .map_err(|e| anyhow!("invalid config file {}: {}", config_filepath.display(), e))?;
+    }
+    serde_json::from_value(config)
+        .map_err(|e| anyhow!("invalid config file {}: {}", config_filepath.display(), e))
+}
+
+fn list_dependencies(path: &Path) -> Result<(), Error> {
+    let generator_yaml: GeneratorYaml = serde_yaml::from_str(&fs::read_to_string(path.join("Generator.yaml"))?)?;
+    let lock = read_lock(path)?;
+    let dependencies = generator_yaml.dependencies.unwrap_or_default();
+    if dependencies.is_empty() {
+        println!("{} has no dependencies", generator_yaml.name);
+        return Ok(());
+    }
+    if lock.as_ref().is_some_and(|lock| !lock.is_up_to_date(&dependencies)) {
+        println!("WARNING: {} is out of date, run `protypo dependency update`", LOCK_FILE);
+    }
+
+    println!("{:<20} {:<12} {:<40} {:<10} VENDORED", "NAME", "VERSION", "URL", "LOCKED");
+    for dependency in &dependencies {
+        let locked = lock.as_ref()
+            .and_then(|lock| lock.dependencies.iter().find(|locked| locked.locks(dependency)))
+            .map(|locked| locked.version.clone())
+            .unwrap_or_else(|| "-".to_string());
+        let vendored = installed_versions(&path.join(VENDOR_DIR), &dependency.name);
+        let vendored = if vendored.is_empty() {
+            "missing".to_string()
+        } else {
+            vendored.iter().map(Version::to_string).collect::<Vec<_>>().join(", ")
+        };
+        let url = dependency.repository.as_ref().map(Url::to_string).unwrap_or_else(|| "local repository".to_string());
+        let name = match &dependency.alias {
+            Some(alias) => format!("{} ({})", dependency.name, alias),
+            None => dependency.name.clone(),
+        };
+        println!("{:<20} {:<12} {:<40} {:<10} {}", name, dependency.version, url, locked, vendored);
+    }
+    Ok(())
+}
+
+/// Resolves a generator by name from the local repository, falling back to the cached indexes of the remote repositories.
+/// A name like `myrepo/foo` is only looked up in the repository `myrepo`.
+async fn resolve_generator(local_repo: &Path, name: &str, requirement: Option<&VersionReq>, policy: &FetchPolicy) -> Result<PathBuf, Error> {
+    let (repository, name) = match name.split_once('/') {
+        Some((repository, name)) => (Some(repository), name),
+        None => (None, name),
+    };
+    if repository.is_none() {
+        match resolve_installed(&local_repo.join("generators"), name, requirement) {
+            Ok((version, path)) => {
+                info!("Using generator {} version {}", name, version);
+                return Ok(path);
+            }
+            Err(e) => debug!("{}, looking in the remote repositories", e),
+        }
+    }
+    let (repository, entry) = resolve_remote(local_repo, repository, name, requirement)?;
+    info!("Using generator {} version {} from repository {}", name, entry.generator_yaml.version, repository.name);
+    Ok(download_generator(&repository, &entry, policy).await?)
+}
+
+/// The policy remote generators are fetched with, from the user config and keyring in the local data directory.
+fn fetch_policy(local_repo: &Path, verify: bool, offline: bool) -> Result<FetchPolicy, Error> {
+    let policy = FetchPolicy::new(local_repo, &read_user_config(local_repo)?, verify, read_keyring(local_repo)?);
+    Ok(FetchPolicy { offline, ..policy })
+}
+
+/// Prints what a generation would write, one row per output file in the order it would be written.
+fn print_plan(plan: &Plan, format: OutputFormat) -> Result<(), Error> {
+    if let OutputFormat::Json = format {
+        println!("{}", serde_json::to_string_pretty(plan)?);
+        return Ok(());
+    }
+    println!("{:<10} {:<50} {:<24} {:<10} SOURCE", "ACTION", "PATH", "GENERATOR", "CONFLICT");
+    for file in &plan.files {
+        let action = serde_json::to_value(file.action)?;
+        let conflict = serde_json::to_value(file.conflict)?;
+        println!("{:<10} {:<50} {:<24} {:<10} {}", action.as_str().unwrap_or_default(), file.path.display(), file.generator,
+            conflict.as_str().unwrap_or("-"), file.source.display());
+    }
+    println!("{} to create, {} to overwrite, {} to inject, {} to skip",
+        plan.count(Action::Create), plan.count(Action::Overwrite), plan.count(Action::Inject), plan.count(Action::Skip));
+    Ok(())
+}
+
+/// What `generate --check` reports: fails naming every file that generating would change.
+fn check_changes(changes: &[Change]) -> Result<(), Error> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    for change in changes {
+        eprintln!("{} is out of date", change.path.display());
+    }
+    Err(anyhow!("{} generated file(s) would change, run `protypo generate` to update them", changes.len()))
+}
+
+/// Prints every schema violation and fails with the schema exit code when there are any.
+fn check_violations(generator: &Generator, violations: &[SchemaViolation]) -> Result<(), Error> {
+    if violations.is_empty() {
+        return Ok(());
+    }
+    for violation in violations {
+        eprintln!("{}", violation);
+    }
+    Err(ProtypoError::schema(generator.key(), Path::new(&generator.base_path).join("values.schema.json"),
+        format!("{} value(s) do not match the schema of their generator", violations.len())).into())
+}
+
+/// Prints one row per generator with its installed versions, described by its latest version.
+fn print_generators(generators: &[InstalledGenerator]) {
+    if generators.is_empty() {
+        println!("No generators found");
+        return;
+    }
+    println!("{:<20} {:<24} {:<10} DESCRIPTION", "NAME", "VERSIONS", "DEPRECATED");
+    for versions in generators.chunk_by(|a, b| a.generator_yaml.name == b.generator_yaml.name) {
+        let latest = &versions[0].generator_yaml;
+        let version_list = versions.iter().map(|installed| installed.version.to_string()).collect::<Vec<_>>().join(", ");
+        let deprecated = if latest.deprecated.unwrap_or(false) { "yes" } else { "no" };
+        println!("{:<20} {:<24} {:<10} {}", latest.name, version_list, deprecated, latest.description.as_deref().unwrap_or(""));
+    }
+}
+
+fn show_generator(path: &Path, field: ShowField) -> Result<(), Error> {
+    let file_name = match field {
+        ShowField::Generator => "Generator.yaml",
+        ShowField::Values => "values.yaml",
+        ShowField::Schema => "values.schema.json",
+        ShowField::Readme => "README.md",
+        ShowField::Entities => {
+            let entities = read_optional_entities(path, "entities")?;
+            println!("{}", serde_json::to_string_pretty(&entities)?);
+            return Ok(());
+        }
+    };
+    let file_path = path.join(file_name);
+    if !file_path.is_file() {
+        return Err(anyhow!("{} has no {}", path.display(), file_name));
+    }
+    print!("{}", fs::read_to_string(file_path)?);
+    Ok(())
+}
+
+fn print_locked_dependencies(dependencies: &[LockedDependency], depth: usize) {
+    for dependency in dependencies {
+        let source = dependency.url.as_ref().map(Url::to_string).unwrap_or_else(|| "local repository".to_string());
+        println!("{}{} {} from {} ({})", "  ".repeat(depth), dependency.name, dependency.version, source, dependency.digest);
+        print_locked_dependencies(&dependency.dependencies, depth + 1);
+    }
+}
+
 /// Function to create the new template package
 fn create_new_template(name: &str) {
     // Define the directory structure and file contents
@@ -257,4 +946,77 @@ fn create_file(path: &str, _content: &str) {
     // let file = File::create(path).unwrap();
     // file.write_all(content.as_bytes()).unwrap();
     println!("Created file: {}", path);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UserConfig;
+    use crate::generator::TemplateRenderer;
+    use crate::signing::Keyring;
+
+    fn plan_template(to: &Path, body: &str) -> Plan {
+        let mut planner = Planner::new("---\n", "===\n", ConflictPolicy::default());
+        let input = format!("to: {}\n===\n{}", to.display(), body);
+        planner.render("app:1.0.0", Path::new("t"), &input, &json!({})).unwrap();
+        planner.into_plan()
+    }
+
+    #[test]
+    fn check_fails_with_exit_code_1_when_a_file_would_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let to = dir.path().join("a.txt");
+        fs::write(&to, "edited by hand\n").unwrap();
+
+        let error = check_changes(&plan_template(&to, "generated\n").changes()).unwrap_err();
+        assert_eq!(exit_code(&error), 1);
+        assert!(error.to_string().contains("1 generated file(s) would change"), "{}", error);
+    }
+
+    #[test]
+    fn check_passes_when_the_output_is_up_to_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let to = dir.path().join("a.txt");
+        fs::write(&to, "generated\n").unwrap();
+
+        assert!(check_changes(&plan_template(&to, "generated\n").changes()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn invalid_values_fail_with_the_schema_exit_code() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("Generator.yaml"), "apiVersion: v1\nname: app\nversion: 1.0.0\n").unwrap();
+        fs::write(dir.path().join("values.yaml"), "port: http\n").unwrap();
+        fs::write(dir.path().join("values.schema.json"), r#"{"properties": {"port": {"type": "integer"}}}"#).unwrap();
+        let policy = FetchPolicy::new(dir.path(), &UserConfig::default(), false, Keyring::default());
+        let generator = Generator::from_directory(dir.path(), &policy).await.unwrap();
+
+        let violations = generator.validate_values(&Context::default()).unwrap();
+        let error = check_violations(&generator, &violations).unwrap_err();
+        assert_eq!(exit_code(&error), 6);
+        assert!(check_violations(&generator, &[]).is_ok());
+    }
+
+    #[test]
+    fn later_values_files_take_precedence_and_keep_deletions() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("first.yaml");
+        let second = dir.path().join("second.json");
+        fs::write(&first, "app:\n  port: 80\n  debug: true\n  tags: [a]\n").unwrap();
+        fs::write(&second, r#"{"app": {"port": 8080, "debug": null, "tags": ["b"]}}"#).unwrap();
+
+        let mut values = json!({"app": {"name": "api"}});
+        layer_values_files(&mut values, &[first.clone(), second.clone()], ListMerge::Append).unwrap();
+        assert_eq!(values, json!({"app": {"name": "api", "port": 8080, "debug": null, "tags": ["a", "b"]}}));
+
+        let mut values = json!({});
+        layer_values_files(&mut values, &[second, first], ListMerge::Replace).unwrap();
+        assert_eq!(values, json!({"app": {"port": 80, "debug": true, "tags": ["a"]}}));
+    }
+
+    #[test]
+    fn exit_codes_follow_the_error() {
+        assert_eq!(exit_code(&anyhow!("other")), 1);
+        assert_eq!(exit_code(&ProtypoError::render("app:1.0.0", "t", "broken").into()), 7);
+    }
+}
@@ -0,0 +1,204 @@
+use std::{fs, io};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use tar::{EntryType, Header};
+use tracing::{debug, info};
+use crate::error::ProtypoError;
+use crate::generator::{validate_generator, GeneratorYaml};
+use crate::lock::bytes_digest;
+use crate::repository::INSTALL_METADATA;
+
+/// File with gitignore-style patterns of the files of a generator that are left out of its package.
+pub const IGNORE_FILE: &str = ".protypoignore";
+
+/// Validates the generator in `generator_dir` and writes `<name>-<version>.tar.gz` and its `.sha256` digest file into `destination`.
+/// Entries are sorted and carry no timestamps or owners, so packaging the same files always gives the same archive.
+/// Returns the metadata of the generator, the path of the archive and its `sha256:<hex>` digest.
+pub fn package(generator_dir: &Path, destination: &Path) -> Result<(GeneratorYaml, PathBuf, String), ProtypoError> {
+    let generator = validate_generator(generator_dir)?;
+    let key = format!("{}:{}", generator.name, generator.version);
+    let file_name = format!("{}-{}.tar.gz", generator.name, generator.version);
+    let archive_path = destination.join(&file_name);
+
+    let ignore = read_ignore_file(generator_dir)
+        .map_err(|e| ProtypoError::parse(&key, generator_dir.join(IGNORE_FILE), e))?;
+    let mut files = Vec::new();
+    collect_files(generator_dir, generator_dir, &ignore, &mut files)
+        .map_err(|e| ProtypoError::load(&key, generator_dir, e))?;
+    let digest_path = destination.join(format!("{}.sha256", file_name));
+    // a previous package written into the generator directory is not part of the new one
+    let previous_package: Vec<PathBuf> = [&archive_path, &digest_path].iter()
+        .filter_map(|path| path.canonicalize().ok())
+        .collect();
+    files.retain(|file| file.canonicalize().map_or(true, |file| !previous_package.contains(&file)));
+
+    let bytes = write_archive(generator_dir, &generator.name, &files)
+        .map_err(|e| ProtypoError::write(&key, &archive_path, e))?;
+    let digest = bytes_digest(&bytes);
+
+    fs::create_dir_all(destination).map_err(|e| ProtypoError::write(&key, destination, e))?;
+    fs::write(&archive_path, &bytes).map_err(|e| ProtypoError::write(&key, &archive_path, e))?;
+    // in the format of `sha256sum`, so the archive can be checked with `sha256sum -c`
+    fs::write(&digest_path, format!("{}  {}\n", digest.trim_start_matches("sha256:"), file_name))
+        .map_err(|e| ProtypoError::write(&key, &digest_path, e))?;
+    info!("Packaged {} files of {} into {}", files.len(), key, archive_path.display());
+    Ok((generator, archive_path, digest))
+}
+
+fn read_ignore_file(generator_dir: &Path) -> Result<Gitignore, io::Error> {
+    let mut builder = GitignoreBuilder::new(generator_dir);
+    let ignore_file = generator_dir.join(IGNORE_FILE);
+    if ignore_file.is_file() {
+        if let Some(e) = builder.add(&ignore_file) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+        }
+    }
+    builder.build().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// Collects the files and directories below `dir` in a stable order, leaving out ignored paths,
+/// version control data and the install metadata of the local repository.
+fn collect_files(root: &Path, dir: &Path, ignore: &Gitignore, files: &mut Vec<PathBuf>) -> Result<(), io::Error> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+    for path in entries {
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        if relative == Path::new(".git") || relative == Path::new(INSTALL_METADATA)
+            || ignore.matched_path_or_any_parents(relative, file_type.is_dir()).is_ignore() {
+            debug!("Ignoring {}", relative.display());
+            continue;
+        }
+        files.push(path.clone());
+        if file_type.is_dir() {
+            collect_files(root, &path, ignore, files)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the files into a gzipped tar archive below a top-level directory named after the generator.
+fn write_archive(root: &Path, top_level_dir: &str, files: &[PathBuf]) -> Result<Vec<u8>, io::Error> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for file in files {
+        let relative = file.strip_prefix(root).unwrap_or(file);
+        let path = Path::new(top_level_dir).join(relative);
+        let metadata = fs::symlink_metadata(file)?;
+        let mut header = Header::new_gnu();
+        header.set_mtime(0);
+        header.set_uid(0);
+        header.set_gid(0);
+        if metadata.is_dir() {
+            header.set_entry_type(EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            builder.append_data(&mut header, &path, io::empty())?;
+        } else if metadata.file_type().is_symlink() {
+            header.set_entry_type(EntryType::Symlink);
+            header.set_mode(0o777);
+            header.set_size(0);
+            builder.append_link(&mut header, &path, fs::read_link(file)?)?;
+        } else {
+            let content = fs::read(file)?;
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(if is_executable(&metadata) { 0o755 } else { 0o644 });
+            header.set_size(content.len() as u64);
+            builder.append_data(&mut header, &path, content.as_slice())?;
+        }
+    }
+    let mut encoder = builder.into_inner()?;
+    encoder.flush()?;
+    encoder.finish()
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{FetchPolicy, UserConfig};
+    use crate::generator::install_template;
+    use crate::lock::directory_digest;
+    use crate::signing::Keyring;
+
+    fn write_generator(dir: &Path) {
+        fs::create_dir_all(dir.join("templates").join("partials")).unwrap();
+        fs::write(dir.join("Generator.yaml"), "apiVersion: v1\nname: api\nversion: 1.0.0\n").unwrap();
+        fs::write(dir.join("values.yaml"), "port: 80\n").unwrap();
+        fs::write(dir.join("templates").join("main.rs.t"), "fn main() {}\n").unwrap();
+        fs::write(dir.join("templates").join("partials").join("_header.tpl"), "// header\n").unwrap();
+    }
+
+    #[test]
+    fn packages_the_same_files_into_the_same_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let generator_dir = dir.path().join("api");
+        write_generator(&generator_dir);
+
+        let (generator, first, first_digest) = package(&generator_dir, &dir.path().join("first")).unwrap();
+        let (_, second, second_digest) = package(&generator_dir, &dir.path().join("second")).unwrap();
+
+        assert_eq!(generator.name, "api");
+        assert_eq!(first.file_name(), Some("api-1.0.0.tar.gz".as_ref()));
+        assert_eq!(fs::read(&first).unwrap(), fs::read(&second).unwrap());
+        assert_eq!(first_digest, second_digest);
+        assert_eq!(first_digest, bytes_digest(&fs::read(&first).unwrap()));
+        let digest_file = fs::read_to_string(dir.path().join("first").join("api-1.0.0.tar.gz.sha256")).unwrap();
+        assert_eq!(digest_file, format!("{}  api-1.0.0.tar.gz\n", first_digest.trim_start_matches("sha256:")));
+    }
+
+    #[test]
+    fn leaves_out_ignored_files_and_previous_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        write_generator(dir.path());
+        fs::write(dir.path().join(IGNORE_FILE), "*.log\ntemplates/partials/\n").unwrap();
+        fs::write(dir.path().join("debug.log"), "noise").unwrap();
+        fs::write(dir.path().join(INSTALL_METADATA), "source: file://api\n").unwrap();
+        package(dir.path(), dir.path()).unwrap();
+
+        let (_, archive, _) = package(dir.path(), dir.path()).unwrap();
+        let mut entries: Vec<String> = tar::Archive::new(flate2::read::GzDecoder::new(fs::File::open(archive).unwrap()))
+            .entries().unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, [".protypoignore", "Generator.yaml", "templates", "templates/main.rs.t", "values.yaml"]
+            .map(|path| format!("api/{}", path)));
+    }
+
+    #[test]
+    fn rejects_invalid_generators() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("Generator.yaml"), "apiVersion: v1\nname: api\nversion: latest\n").unwrap();
+
+        assert_eq!(package(dir.path(), dir.path()).unwrap_err().exit_code(), 5);
+    }
+
+    #[tokio::test]
+    async fn installs_the_packaged_generator() {
+        let dir = tempfile::tempdir().unwrap();
+        let generator_dir = dir.path().join("api");
+        write_generator(&generator_dir);
+        let (_, archive, _) = package(&generator_dir, dir.path()).unwrap();
+
+        let policy = FetchPolicy::new(&dir.path().join("data"), &UserConfig::default(), false, Keyring::default());
+        let (_, installed) = install_template(&archive.display().to_string(), &dir.path().join("generators"), false, &policy).await.unwrap();
+
+        assert_eq!(installed, dir.path().join("generators").join("api").join("1.0.0"));
+        assert_eq!(directory_digest(&installed).unwrap(), directory_digest(&generator_dir).unwrap());
+    }
+}
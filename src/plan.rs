@@ -0,0 +1,485 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::{fs, io};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use rrgen::{FsDriver, Printer, RRgen};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use similar::TextDiff;
+use crate::conflict::{ConflictPolicy, ConflictStrategy};
+use crate::generator::TemplateRenderer;
+
+/// What generating would do to an output file.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Create,
+    Overwrite,
+    Skip,
+    Inject,
+}
+
+/// An output file of a generation, with the generator and the template or file that produced it.
+#[derive(Serialize, Debug, Clone)]
+pub struct PlannedFile {
+    pub path: PathBuf,
+    pub action: Action,
+    /// key of the generator, `name:version`
+    pub generator: String,
+    /// the template, or the file of the `files` directory, the output comes from
+    pub source: PathBuf,
+    /// the `message` of the frontmatter of the template
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// the `on_conflict` of the frontmatter of the template
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_conflict: Option<ConflictStrategy>,
+    /// what happens to the file, which exists with other content than generated, as the conflict policy decides
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflict: Option<ConflictStrategy>,
+}
+
+/// Everything a generation would write, in the order it would be written, without touching the filesystem.
+#[derive(Serialize, Debug, Default)]
+pub struct Plan {
+    pub files: Vec<PlannedFile>,
+    /// the content planned for each path so far, later templates see the output of earlier ones
+    #[serde(skip)]
+    contents: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl Plan {
+    /// Content of `path` at this point of the generation: planned by an earlier template or on disk.
+    fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        self.contents.get(path).cloned().or_else(|| fs::read(path).ok())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.contents.contains_key(path) || path.exists()
+    }
+
+    /// `frontmatter` is the one of the document writing the file, if it comes from a template.
+    fn push(&mut self, generator: &str, source: &Path, path: PathBuf, action: Action, frontmatter: Option<&FrontMatter>) {
+        self.files.push(PlannedFile {
+            path,
+            action,
+            generator: generator.to_string(),
+            source: source.to_path_buf(),
+            message: frontmatter.and_then(|frontmatter| frontmatter.message.clone()),
+            on_conflict: frontmatter.and_then(|frontmatter| frontmatter.on_conflict),
+            conflict: None,
+        });
+    }
+
+    /// Number of files per action.
+    pub fn count(&self, action: Action) -> usize {
+        self.files.iter().filter(|file| file.action == action).count()
+    }
+
+    /// The files whose content on disk the generation would change, each once with the content it would end up with,
+    /// in the order they are first written.
+    pub fn changes(&self) -> Vec<Change> {
+        let mut seen = HashSet::new();
+        self.files.iter()
+            .filter(|file| seen.insert(&file.path))
+            .filter_map(|file| {
+                let new = self.contents.get(&file.path)?;
+                let old = fs::read(&file.path).ok();
+                (old.as_ref() != Some(new)).then(|| Change { path: file.path.clone(), old, new: new.clone() })
+            })
+            .collect()
+    }
+}
+
+/// An output file that would be created or whose content would change.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub path: PathBuf,
+    /// the current content, none when the file does not exist yet
+    pub old: Option<Vec<u8>>,
+    pub new: Vec<u8>,
+}
+
+impl Change {
+    /// Unified diff from the current to the generated content, with colored lines when `color` is set.
+    pub fn unified_diff(&self, color: bool) -> String {
+        let old_name = match self.old {
+            Some(_) => format!("a/{}", self.path.display()),
+            None => "/dev/null".to_string(),
+        };
+        let new_name = format!("b/{}", self.path.display());
+        let old = self.old.as_deref().unwrap_or_default();
+        let (Ok(old), Ok(new)) = (std::str::from_utf8(old), std::str::from_utf8(&self.new)) else {
+            return format!("Binary files {} and {} differ\n", old_name, new_name);
+        };
+        let diff = TextDiff::from_lines(old, new)
+            .unified_diff()
+            .context_radius(3)
+            .header(&old_name, &new_name)
+            .to_string();
+        if !color {
+            return diff;
+        }
+        diff.lines()
+            .enumerate()
+            .map(|(index, line)| {
+                let code = match line.chars().next() {
+                    // the file names
+                    _ if index < 2 => "1",
+                    Some('+') => "32",
+                    Some('-') => "31",
+                    Some('@') => "36",
+                    _ => return format!("{}\n", line),
+                };
+                format!("\x1b[{}m{}\x1b[0m\n", code, line)
+            })
+            .collect()
+    }
+}
+
+/// The keys of the frontmatter of a rendered document that end up in the plan, rrgen handles the others.
+#[derive(Deserialize, Debug, Default)]
+struct FrontMatter {
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    on_conflict: Option<ConflictStrategy>,
+}
+
+/// What the planning rrgen shares with its [`Planner`]: the plan so far and what is being rendered.
+#[derive(Default)]
+struct Rendering {
+    plan: Plan,
+    policy: ConflictPolicy,
+    /// key of the generator of the template being rendered
+    generator: String,
+    template: PathBuf,
+    /// the frontmatter of the document being rendered
+    frontmatter: FrontMatter,
+    /// why the frontmatter of a document could not be read, reported once rrgen returns
+    invalid: Option<String>,
+    /// the file rrgen reported creating or overwriting, whose content it writes next
+    pending: Option<PathBuf>,
+}
+
+impl Rendering {
+    fn push(&mut self, path: &Path, action: Action) {
+        let Rendering { plan, generator, template, frontmatter, .. } = self;
+        plan.push(generator, template, path.to_path_buf(), action, Some(frontmatter));
+        if matches!(action, Action::Create | Action::Overwrite) {
+            self.pending = Some(path.to_path_buf());
+        }
+    }
+
+    /// Records the content written to `path`. When the file was reported as created or overwritten, rather than injected into,
+    /// and exists on disk with other content, the conflict policy decides what happens to it, as it does when generating.
+    /// A skipped file keeps its content on disk, so later templates see that one.
+    fn write(&mut self, path: &Path, content: Vec<u8>) {
+        let replaced = self.pending.take().is_some_and(|pending| pending == path);
+        if replaced && !self.plan.contents.contains_key(path) {
+            let file = self.plan.files.last_mut().filter(|file| file.path == path);
+            if let Some(file) = file.filter(|_| fs::read(path).is_ok_and(|old| old != content)) {
+                let strategy = self.policy.strategy(file);
+                file.conflict = Some(strategy);
+                if strategy == ConflictStrategy::Skip {
+                    file.action = Action::Skip;
+                    return;
+                }
+            }
+        }
+        self.plan.contents.insert(path.to_path_buf(), content);
+    }
+}
+
+/// The filesystem of the planning rrgen: writes go into the plan, reads see the plan over the disk.
+struct PlanFs(Rc<RefCell<Rendering>>);
+
+impl FsDriver for PlanFs {
+    fn write_file(&self, path: &Path, content: &str) -> rrgen::Result<()> {
+        self.0.borrow_mut().write(path, content.as_bytes().to_vec());
+        Ok(())
+    }
+
+    fn read_file(&self, path: &Path) -> rrgen::Result<String> {
+        let content = self.0.borrow().plan.read(path)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("{} does not exist", path.display())))?;
+        Ok(String::from_utf8_lossy(&content).into_owned())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.0.borrow().plan.exists(path)
+    }
+}
+
+/// Records what the planning rrgen reports doing to each file.
+struct PlanPrinter(Rc<RefCell<Rendering>>);
+
+impl Printer for PlanPrinter {
+    fn overwrite_file(&self, file_to: &Path) {
+        self.0.borrow_mut().push(file_to, Action::Overwrite);
+    }
+
+    fn skip_exists(&self, file_to: &Path) {
+        self.0.borrow_mut().push(file_to, Action::Skip);
+    }
+
+    fn add_file(&self, file_to: &Path) {
+        self.0.borrow_mut().push(file_to, Action::Create);
+    }
+
+    fn injected(&self, file_to: &Path) {
+        self.0.borrow_mut().push(file_to, Action::Inject);
+    }
+
+    fn frontmatter(&self, frontmatter: &Value) {
+        let mut rendering = self.0.borrow_mut();
+        rendering.frontmatter = match serde_json::from_value(frontmatter.clone()) {
+            Ok(frontmatter) => frontmatter,
+            Err(e) => {
+                rendering.invalid.get_or_insert(format!("invalid frontmatter: {}", e));
+                FrontMatter::default()
+            }
+        };
+    }
+}
+
+/// Renders templates with rrgen into a [`Plan`] instead of onto the filesystem, so that the plan holds exactly
+/// what rrgen would write, with the conflicts with existing files resolved by the policy as far as it can be without asking.
+pub struct Planner {
+    rrgen: RRgen,
+    rendering: Rc<RefCell<Rendering>>,
+}
+
+impl Planner {
+    /// `document_separator` separates the documents of a rendered template, `frontmatter_separator` the frontmatter of a document from its body.
+    pub fn new(document_separator: &str, frontmatter_separator: &str, policy: ConflictPolicy) -> Self {
+        let rendering = Rc::new(RefCell::new(Rendering { policy, ..Rendering::default() }));
+        let mut rrgen = RRgen::default()
+            .with_fs(Box::new(PlanFs(rendering.clone())))
+            .with_printer(Box::new(PlanPrinter(rendering.clone())));
+        rrgen.document_separator = document_separator.to_string();
+        rrgen.frontmatter_separator = frontmatter_separator.to_string();
+        Planner { rrgen, rendering }
+    }
+
+    /// Plans copying a file of the `files` directory of a generator to `destination`.
+    pub fn add_file(&mut self, generator: &str, source: &Path, destination: PathBuf) -> Result<(), io::Error> {
+        let content = fs::read(source)?;
+        let mut rendering = self.rendering.borrow_mut();
+        rendering.generator = generator.to_string();
+        rendering.template = source.to_path_buf();
+        rendering.frontmatter = FrontMatter::default();
+        let action = if rendering.plan.exists(&destination) { Action::Overwrite } else { Action::Create };
+        rendering.push(&destination, action);
+        rendering.write(&destination, content);
+        Ok(())
+    }
+
+    pub fn into_plan(self) -> Plan {
+        self.rendering.take().plan
+    }
+}
+
+impl TemplateRenderer for Planner {
+    fn add_template_dir(&mut self, dir: &Path) -> Result<(), String> {
+        self.rrgen.add_template_dir(dir)
+    }
+
+    fn render(&mut self, generator: &str, template: &Path, input: &str, context: &Value) -> Result<(), String> {
+        {
+            let mut rendering = self.rendering.borrow_mut();
+            rendering.generator = generator.to_string();
+            rendering.template = template.to_path_buf();
+        }
+        self.rrgen.render(generator, template, input, context)?;
+        match self.rendering.borrow_mut().invalid.take() {
+            Some(invalid) => Err(invalid),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    const GENERATOR: &str = "app:1.0.0";
+
+    /// Plans the templates in order, each given as the frontmatter and body of a single document.
+    fn plan(templates: &[(String, &str)]) -> Plan {
+        let mut planner = Planner::new("---\n", "===\n", ConflictPolicy::default());
+        for (index, (frontmatter, body)) in templates.iter().enumerate() {
+            let input = format!("{}\n===\n{}", frontmatter, body);
+            let template = PathBuf::from(format!("templates/{}.t", index));
+            planner.render(GENERATOR, &template, &input, &json!({"values": {"name": "world"}})).unwrap();
+        }
+        planner.into_plan()
+    }
+
+    fn path(dir: &TempDir, name: &str) -> PathBuf {
+        dir.path().join(name)
+    }
+
+    #[test]
+    fn creates_files_without_writing_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan = plan(&[(format!("to: {}", path(&dir, "a.txt").display()), "hello {{ values.name }}\n")]);
+
+        assert_eq!(plan.files.len(), 1);
+        assert_eq!(plan.files[0].action, Action::Create);
+        assert_eq!(plan.files[0].generator, GENERATOR);
+        assert_eq!(plan.files[0].source, Path::new("templates/0.t"));
+        assert_eq!(plan.changes()[0].new, b"hello world\n");
+        assert!(!path(&dir, "a.txt").exists());
+    }
+
+    #[test]
+    fn overwrites_existing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(path(&dir, "a.txt"), "old\n").unwrap();
+        let plan = plan(&[(format!("to: {}", path(&dir, "a.txt").display()), "new\n")]);
+
+        assert_eq!(plan.files[0].action, Action::Overwrite);
+        assert_eq!(fs::read_to_string(path(&dir, "a.txt")).unwrap(), "old\n");
+    }
+
+    #[test]
+    fn skips_existing_files_with_skip_exists() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(path(&dir, "a.txt"), "old\n").unwrap();
+        let plan = plan(&[(format!("to: {}\nskip_exists: true", path(&dir, "a.txt").display()), "new\n")]);
+
+        assert_eq!(plan.files[0].action, Action::Skip);
+        assert!(plan.changes().is_empty());
+    }
+
+    #[test]
+    fn later_templates_see_the_planned_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let to = path(&dir, "a.txt");
+        let plan = plan(&[
+            (format!("to: {}", to.display()), "first\n"),
+            (format!("to: {}\nskip_exists: true", to.display()), "second\n"),
+        ]);
+
+        let actions: Vec<Action> = plan.files.iter().map(|file| file.action).collect();
+        assert_eq!(actions, [Action::Create, Action::Skip]);
+        assert_eq!(plan.changes()[0].new, b"first\n");
+    }
+
+    #[test]
+    fn injects_into_existing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(path(&dir, "mod.rs"), "mod a;\nmod c;\n").unwrap();
+        let frontmatter = format!("to: {}\ninjections:\n- into: {}\n  after: \"^mod a;\"\n  content: \"mod b;\"",
+            path(&dir, "b.rs").display(), path(&dir, "mod.rs").display());
+        let plan = plan(&[(frontmatter, "// b\n")]);
+
+        let actions: Vec<(Action, PathBuf)> = plan.files.iter().map(|file| (file.action, file.path.clone())).collect();
+        assert_eq!(actions, [(Action::Create, path(&dir, "b.rs")), (Action::Inject, path(&dir, "mod.rs"))]);
+        let injected = plan.changes().into_iter().find(|change| change.path == path(&dir, "mod.rs")).unwrap();
+        assert_eq!(String::from_utf8(injected.new).unwrap(), "mod a;\nmod b;\nmod c;");
+    }
+
+    #[test]
+    fn plans_copied_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(path(&dir, "source.txt"), "copied\n").unwrap();
+        fs::write(path(&dir, "existing.txt"), "old\n").unwrap();
+        let mut planner = Planner::new("---\n", "===\n", ConflictPolicy::default());
+        planner.add_file(GENERATOR, &path(&dir, "source.txt"), path(&dir, "new.txt")).unwrap();
+        planner.add_file(GENERATOR, &path(&dir, "source.txt"), path(&dir, "existing.txt")).unwrap();
+        let plan = planner.into_plan();
+
+        let actions: Vec<Action> = plan.files.iter().map(|file| file.action).collect();
+        assert_eq!(actions, [Action::Create, Action::Overwrite]);
+        assert!(!path(&dir, "new.txt").exists());
+    }
+
+    #[test]
+    fn rejects_invalid_frontmatter_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut planner = Planner::new("---\n", "===\n", ConflictPolicy::default());
+        let input = format!("to: {}\non_conflict: sometimes\n===\nbody\n", path(&dir, "a.txt").display());
+        let error = planner.render(GENERATOR, Path::new("t"), &input, &json!({})).unwrap_err();
+        assert!(error.contains("invalid frontmatter"), "{}", error);
+    }
+
+    #[test]
+    fn changes_leave_out_unchanged_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(path(&dir, "same.txt"), "same\n").unwrap();
+        let plan = plan(&[
+            (format!("to: {}", path(&dir, "same.txt").display()), "same\n"),
+            (format!("to: {}", path(&dir, "new.txt").display()), "new\n"),
+        ]);
+
+        let changed: Vec<PathBuf> = plan.changes().into_iter().map(|change| change.path).collect();
+        assert_eq!(changed, [path(&dir, "new.txt")]);
+    }
+
+    #[test]
+    fn changes_hold_the_final_content_of_each_file_once() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(path(&dir, "lib.rs"), "mod a;\n").unwrap();
+        let plan = plan(&[
+            (format!("to: {}", path(&dir, "lib.rs").display()), "mod b;\n"),
+            (format!("to: {}\ninjections:\n- into: {}\n  append: true\n  content: \"mod c;\"",
+                path(&dir, "c.rs").display(), path(&dir, "lib.rs").display()), "// c\n"),
+        ]);
+
+        let changes = plan.changes();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].path, path(&dir, "lib.rs"));
+        assert_eq!(changes[0].old.as_deref(), Some(b"mod a;\n".as_slice()));
+        assert_eq!(changes[0].new, b"mod b;\n\nmod c;");
+        assert_eq!(changes[1].old, None);
+    }
+
+    #[test]
+    fn diffs_new_and_changed_files() {
+        let created = Change { path: PathBuf::from("out/a.txt"), old: None, new: b"a\n".to_vec() };
+        assert_eq!(created.unified_diff(false), "--- /dev/null\n+++ b/out/a.txt\n@@ -0,0 +1 @@\n+a\n");
+
+        let changed = Change { path: PathBuf::from("out/b.txt"), old: Some(b"x\ny\n".to_vec()), new: b"x\nz\n".to_vec() };
+        assert_eq!(changed.unified_diff(false), "--- a/out/b.txt\n+++ b/out/b.txt\n@@ -1,2 +1,2 @@\n x\n-y\n+z\n");
+    }
+
+    #[test]
+    fn colors_diffs() {
+        let changed = Change { path: PathBuf::from("b.txt"), old: Some(b"y\n".to_vec()), new: b"z\n".to_vec() };
+        let diff = changed.unified_diff(true);
+        assert!(diff.contains("\x1b[1m--- a/b.txt\x1b[0m\n"), "{}", diff);
+        assert!(diff.contains("\x1b[31m-y\x1b[0m\n"), "{}", diff);
+        assert!(diff.contains("\x1b[32m+z\x1b[0m\n"), "{}", diff);
+        assert!(diff.contains("\x1b[36m@@"), "{}", diff);
+    }
+
+    #[test]
+    fn does_not_diff_binary_files() {
+        let changed = Change { path: PathBuf::from("logo.png"), old: Some(vec![0xff, 0x00]), new: vec![0xfe] };
+        assert_eq!(changed.unified_diff(false), "Binary files a/logo.png and b/logo.png differ\n");
+    }
+
+    #[test]
+    fn serializes_to_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let to = path(&dir, "a.txt");
+        let plan = plan(&[(format!("to: {}\nmessage: created a\non_conflict: skip", to.display()), "a\n")]);
+
+        assert_eq!(serde_json::to_value(&plan).unwrap(), json!({
+            "files": [{
+                "path": to,
+                "action": "create",
+                "generator": GENERATOR,
+                "source": "templates/0.t",
+                "message": "created a",
+                "on_conflict": "skip",
+            }]
+        }));
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::BTreeMap;
+use std::{fs, io};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use reqwest::Url;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use tempfile::tempdir;
+use tracing::{debug, info, warn};
+use crate::archive::{self, ArchiveFormat};
+use crate::cache::{extract_cached, read_cached};
+use crate::config::FetchPolicy;
+use crate::generator::{glob_below, GeneratorYaml};
+use crate::lock::bytes_digest;
+use crate::signing::check_signature;
+use crate::trust::{check_source, confirm_source};
+
+/// File in the local data directory listing the remote repositories added with `protypo repo add`.
+pub const REPOSITORIES_FILE: &str = "repositories.yaml";
+/// File at the root of a remote repository listing its generators.
+pub const INDEX_FILE: &str = "index.yaml";
+/// Directory in the local data directory where the indexes of the remote repositories are cached.
+const INDEX_CACHE_DIR: &str = "cache";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Repositories {
+    #[serde(rename = "repositories", default)]
+    pub repositories: Vec<RemoteRepository>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoteRepository {
+    #[serde(rename = "name")]
+    pub name: String,
+
+    #[serde(rename = "url")]
+    pub url: Url,
+}
+
+/// Content of the `index.yaml` of a repository, the generators of the repository by name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Index {
+    #[serde(rename = "apiVersion")]
+    pub api_version: String,
+
+    /// the versions of each generator, from the highest to the lowest
+    #[serde(rename = "entries", default)]
+    pub entries: BTreeMap<String, Vec<IndexEntry>>,
+}
+
+/// A packaged version of a generator: the metadata of its `Generator.yaml`, where to download it and the digest of the archive.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexEntry {
+    #[serde(flatten)]
+    pub generator_yaml: GeneratorYaml,
+
+    /// urls of the archive, relative ones are resolved against the url of the repository
+    #[serde(rename = "urls")]
+    pub urls: Vec<String>,
+
+    /// sha256 digest of the archive
+    #[serde(rename = "digest")]
+    pub digest: String,
+}
+
+impl RemoteRepository {
+    /// Url of a file of the repository.
+    fn join(&self, path: &str) -> Result<Url, io::Error> {
+        join_url(&self.url, path)
+    }
+}
+
+/// Resolves `path` against `base`, which is always treated as a directory even without a trailing slash.
+fn join_url(base: &Url, path: &str) -> Result<Url, io::Error> {
+    let mut base = base.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    base.join(path).map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("Invalid url {} relative to {}: {}", path, base, e)))
+}
+
+/// Parses the url of a repository, a local directory is accepted as well and turned into a `file://` url.
+pub fn parse_repository_url(url: &str) -> Result<Url, io::Error> {
+    let path = Path::new(url);
+    if path.is_dir() {
+        let path = path.canonicalize()?;
+        return Url::from_directory_path(&path)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("Cannot convert {} to a url", path.display())));
+    }
+    Url::parse(url).map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("Invalid repository url {}: {}", url, e)))
+}
+
+pub fn read_repositories(data_dir: &Path) -> Result<Repositories, io::Error> {
+    let path = data_dir.join(REPOSITORIES_FILE);
+    if !path.is_file() {
+        return Ok(Repositories::default());
+    }
+    serde_yaml::from_str(&fs::read_to_string(&path)?)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("Cannot deserialize file {:?} due to error:{:?}", path, e)))
+}
+
+pub fn write_repositories(data_dir: &Path, repositories: &Repositories) -> Result<(), io::Error> {
+    let content = serde_yaml::to_string(repositories)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    fs::create_dir_all(data_dir)?;
+    fs::write(data_dir.join(REPOSITORIES_FILE), content)
+}
+
+/// Repository names are used as file names in the index cache and in `<repository>/<name>@<version>` references.
+pub fn validate_repository_name(name: &str) -> Result<(), io::Error> {
+    if name.is_empty() || name.contains(['/', '\\', '@']) || name.starts_with('.') {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("Invalid repository name '{}'", name)));
+    }
+    Ok(())
+}
+
+fn index_cache_path(data_dir: &Path, repository: &str) -> PathBuf {
+    data_dir.join(INDEX_CACHE_DIR).join(format!("{}-index.yaml", repository))
+}
+
+/// Downloads the index of a repository through the download cache and caches it in the local data directory.
+pub async fn update_index(policy: &FetchPolicy, repository: &RemoteRepository) -> Result<Index, io::Error> {
+    validate_repository_name(&repository.name)?;
+    let data_dir = policy.data_dir.as_path();
+    let url = repository.join(INDEX_FILE)?;
+    info!("Downloading index of repository {} from {}", repository.name, url);
+    let content = read_cached(policy, &url).await?;
+    let index: Index = serde_yaml::from_slice(&content)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("Invalid index {}: {}", url, e)))?;
+    let cache_path = index_cache_path(data_dir, &repository.name);
+    fs::create_dir_all(data_dir.join(INDEX_CACHE_DIR))?;
+    fs::write(&cache_path, content)?;
+    debug!("Cached index of repository {} in {}", repository.name, cache_path.display());
+    Ok(index)
+}
+
+pub fn read_cached_index(data_dir: &Path, repository: &str) -> Result<Index, io::Error> {
+    let path = index_cache_path(data_dir, repository);
+    let content = fs::read_to_string(&path)
+        .map_err(|e| io::Error::new(e.kind(), format!("No cached index for repository {}, run `protypo repo update`: {}", repository, e)))?;
+    serde_yaml::from_str(&content)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("Cannot deserialize file {:?} due to error:{:?}", path, e)))
+}
+
+pub fn remove_cached_index(data_dir: &Path, repository: &str) -> Result<(), io::Error> {
+    let path = index_cache_path(data_dir, repository);
+    if path.is_file() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Finds the highest version of `name` matching `requirement` in the cached indexes,
+/// of every repository or only of `repository`.
+pub fn resolve_remote(data_dir: &Path, repository: Option<&str>, name: &str, requirement: Option<&VersionReq>) -> Result<(RemoteRepository, IndexEntry), io::Error> {
+    let repositories = read_repositories(data_dir)?;
+    if let Some(repository) = repository {
+        if !repositories.repositories.iter().any(|remote| remote.name == repository) {
+            return Err(io::Error::new(ErrorKind::NotFound, format!("Repository {} has not been added, run `protypo repo add`", repository)));
+        }
+    }
+    let mut candidates: Vec<(Version, RemoteRepository, IndexEntry)> = Vec::new();
+    for remote in repositories.repositories.iter().filter(|remote| repository.is_none_or(|repository| remote.name == repository)) {
+        let index = match read_cached_index(data_dir, &remote.name) {
+            Ok(index) => index,
+            // a broken repository only fails the lookup when it was asked for
+            Err(e) if repository.is_none() => {
+                warn!("Skipping repository {}: {}", remote.name, e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        for entry in index.entries.get(name).into_iter().flatten() {
+            match Version::parse(&entry.generator_yaml.version) {
+                Ok(version) if requirement.is_none_or(|requirement| requirement.matches(&version)) =>
+                    candidates.push((version, remote.clone(), entry.clone())),
+                Ok(_) => {}
+                Err(e) => debug!("Skipping {} {} of repository {}: {}", name, entry.generator_yaml.version, remote.name, e),
+            }
+        }
+    }
+
+    // the first repository wins between equal versions
+    candidates.sort_by(|a, b| b.0.cmp(&a.0));
+    let (version, remote, entry) = candidates.into_iter().next()
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("No version of generator {} matching {} found in the repositories",
+            name, requirement.map(VersionReq::to_string).unwrap_or_else(|| "*".to_string()))))?;
+    debug!("Resolved generator {} {:?} to version {} of repository {}", name, requirement.map(VersionReq::to_string), version, remote.name);
+    Ok((remote, entry))
+}
+
+/// Resolves a `repo://<repository>/<name>` url through the cached index of the repository
+/// to the highest version matching `requirement` and downloads it.
+pub async fn fetch_from_repository(data_dir: &Path, url: &Url, requirement: &VersionReq, policy: &FetchPolicy) -> Result<PathBuf, io::Error> {
+    let repository = url.host_str()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("Url {} has no repository, expected repo://<repository>/<name>", url)))?;
+    let name = url.path().trim_matches('/');
+    if name.is_empty() || name.contains('/') {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("Url {} has no generator name, expected repo://<repository>/<name>", url)));
+    }
+    let (remote, entry) = resolve_remote(data_dir, Some(repository), name, Some(requirement))?;
+    download_generator(&remote, &entry, policy).await
+}
+
+/// Downloads the archive of an index entry through the download cache, checks its digest and extracts it into the cache.
+/// Both the repository and the archive must be allowed sources, see [`check_source`], and the user confirms the generator
+/// unless both are on allowed hosts. When the policy asks for signatures, the archive is verified against the provenance file next to it.
+pub async fn download_generator(repository: &RemoteRepository, entry: &IndexEntry, policy: &FetchPolicy) -> Result<PathBuf, io::Error> {
+    let url = entry.urls.first()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, format!("Generator {} {} of repository {} has no url",
+            entry.generator_yaml.name, entry.generator_yaml.version, repository.name)))?;
+    let url = repository.join(url)?;
+    let repository_needs_consent = check_source(policy, repository.url.as_str())?;
+    let needs_consent = check_source(policy, url.as_str())? || repository_needs_consent;
+    info!("Downloading generator {} {} from {}", entry.generator_yaml.name, entry.generator_yaml.version, url);
+    let bytes = read_cached(policy, &url).await?;
+    let digest = bytes_digest(&bytes);
+    if digest != entry.digest {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("Digest of {} does not match the index of repository {}: expected {}, got {}",
+            url, repository.name, entry.digest, digest)));
+    }
+    let extracted_dir = extract_cached(&policy.data_dir, &bytes)?;
+    check_signature(policy, &url, &bytes, &extracted_dir).await?;
+    if needs_consent {
+        confirm_source(policy, url.as_str(), &extracted_dir)?;
+    }
+    Ok(extracted_dir)
+}
+
+/// Reads a `file://` url from disk or downloads an `http(s)://` one, so a repository can be a plain directory or any static file server.
+pub async fn read_url(url: &Url) -> Result<Vec<u8>, io::Error> {
+    match url.scheme() {
+        "file" => {
+            let path = url.to_file_path()
+                .map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("Invalid file url {}", url)))?;
+            fs::read(&path).map_err(|e| io::Error::new(e.kind(), format!("Cannot read {}: {}", path.display(), e)))
+        }
+        "http" | "https" => {
+            let response = reqwest::get(url.clone()).await
+                .map_err(|e| io::Error::other(format!("Failed to download file {} due to error: {}", url, e)))?;
+            if !response.status().is_success() {
+                return Err(io::Error::other(format!("Failed to download file from {}. Status code: {}", url, response.status())));
+            }
+            let bytes = response.bytes().await
+                .map_err(|e| io::Error::other(format!("Failed to read response bytes of file downloaded from url {} due to error: {}", url, e)))?;
+            Ok(bytes.to_vec())
+        }
+        scheme => Err(io::Error::new(ErrorKind::InvalidInput, format!("Unsupported repository url scheme {}", scheme))),
+    }
+}
+
+/// Builds the index of the generator archives below `dir`, with urls relative to `dir` or prefixed with `base_url`.
+/// Files that are not archives, or archives without a `Generator.yaml`, are skipped.
+pub fn build_index(dir: &Path, base_url: Option<&Url>) -> Result<Index, io::Error> {
+    let mut files: Vec<PathBuf> = glob_below(dir, "**/*")?
+        .filter_map(|entry| entry.ok())
+        .filter(|file| file.is_file())
+        .collect();
+    files.sort();
+
+    let mut entries: BTreeMap<String, Vec<IndexEntry>> = BTreeMap::new();
+    for file in files {
+        let bytes = fs::read(&file)?;
+        if ArchiveFormat::detect(&bytes).is_none() {
+            continue;
+        }
+        let temp_dir = tempdir()?;
+        let generator_yaml = archive::extract(&bytes, temp_dir.path())
+            .and_then(|_| fs::read_to_string(temp_dir.path().join("Generator.yaml")))
+            .and_then(|content| serde_yaml::from_str::<GeneratorYaml>(&content).map_err(|e| io::Error::new(ErrorKind::InvalidData, e)));
+        let generator_yaml = match generator_yaml {
+            Ok(generator_yaml) => generator_yaml,
+            Err(e) => {
+                warn!("Skipping {}: {}", file.display(), e);
+                continue;
+            }
+        };
+
+        let relative = file.strip_prefix(dir).unwrap_or(&file)
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+            .join("/");
+        let url = match base_url {
+            Some(base_url) => join_url(base_url, &relative)?.to_string(),
+            None => relative,
+        };
+        debug!("Indexing {} {} from {}", generator_yaml.name, generator_yaml.version, url);
+        entries.entry(generator_yaml.name.clone()).or_default().push(IndexEntry {
+            generator_yaml,
+            urls: vec![url],
+            digest: bytes_digest(&bytes),
+        });
+    }
+
+    for versions in entries.values_mut() {
+        versions.sort_by(|a, b| {
+            let a = Version::parse(&a.generator_yaml.version).ok();
+            let b = Version::parse(&b.generator_yaml.version).ok();
+            b.cmp(&a)
+        });
+    }
+    Ok(Index { api_version: "v1".to_string(), entries })
+}
+
+pub fn write_index(dir: &Path, index: &Index) -> Result<(), io::Error> {
+    let content = serde_yaml::to_string(index)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    fs::write(dir.join(INDEX_FILE), content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::package;
+
+    /// Packages a generator with the given name and version into `dir`.
+    fn write_package(dir: &Path, name: &str, version: &str) -> PathBuf {
+        let generator_dir = tempdir().unwrap();
+        fs::write(generator_dir.path().join("Generator.yaml"), format!("apiVersion: v1\nname: {}\nversion: {}\n", name, version)).unwrap();
+        package(generator_dir.path(), dir).unwrap().1
+    }
+
+    fn entry(name: &str, version: &str, url: &str) -> IndexEntry {
+        IndexEntry {
+            generator_yaml: serde_yaml::from_str(&format!("apiVersion: v1\nname: {}\nversion: {}\n", name, version)).unwrap(),
+            urls: vec![url.to_string()],
+            digest: "sha256:00".to_string(),
+        }
+    }
+
+    /// Adds the repositories and caches their indexes, a repository without entries has no cached index.
+    fn add_repositories(data_dir: &Path, repositories: &[(&str, Vec<IndexEntry>)]) {
+        let mut added = Repositories::default();
+        fs::create_dir_all(data_dir.join(INDEX_CACHE_DIR)).unwrap();
+        for (name, entries) in repositories {
+            added.repositories.push(RemoteRepository { name: name.to_string(), url: Url::parse(&format!("https://{}.example.com/", name)).unwrap() });
+            if !entries.is_empty() {
+                let mut index = Index { api_version: "v1".to_string(), entries: BTreeMap::new() };
+                for entry in entries {
+                    index.entries.entry(entry.generator_yaml.name.clone()).or_default().push(entry.clone());
+                }
+                fs::write(index_cache_path(data_dir, name), serde_yaml::to_string(&index).unwrap()).unwrap();
+            }
+        }
+        write_repositories(data_dir, &added).unwrap();
+    }
+
+    #[test]
+    fn indexes_the_archives_below_a_directory() {
+        let dir = tempdir().unwrap();
+        let archive = write_package(&dir.path().join("api"), "api", "1.0.0");
+        write_package(&dir.path().join("api"), "api", "1.10.0");
+        write_package(dir.path(), "web", "0.1.0");
+        fs::write(dir.path().join("README.md"), "not an archive").unwrap();
+
+        let index = build_index(dir.path(), None).unwrap();
+
+        assert_eq!(index.entries.keys().collect::<Vec<_>>(), ["api", "web"]);
+        let api: Vec<(&str, &str)> = index.entries["api"].iter().map(|entry| (entry.generator_yaml.version.as_str(), entry.urls[0].as_str())).collect();
+        assert_eq!(api, [("1.10.0", "api/api-1.10.0.tar.gz"), ("1.0.0", "api/api-1.0.0.tar.gz")]);
+        assert_eq!(index.entries["api"][1].digest, bytes_digest(&fs::read(archive).unwrap()));
+    }
+
+    #[test]
+    fn prefixes_urls_with_the_base_url() {
+        let dir = tempdir().unwrap();
+        write_package(dir.path(), "web", "0.1.0");
+
+        let index = build_index(dir.path(), Some(&Url::parse("https://example.com/generators").unwrap())).unwrap();
+        assert_eq!(index.entries["web"][0].urls, ["https://example.com/generators/web-0.1.0.tar.gz"]);
+    }
+
+    #[test]
+    fn skips_archives_without_a_generator() {
+        let dir = tempdir().unwrap();
+        let content = tempdir().unwrap();
+        fs::write(content.path().join("README.md"), "no generator").unwrap();
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+        builder.append_dir_all("docs", content.path()).unwrap();
+        fs::write(dir.path().join("docs.tar.gz"), builder.into_inner().unwrap().finish().unwrap()).unwrap();
+
+        assert!(build_index(dir.path(), None).unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn resolves_the_highest_matching_version_of_all_repositories() {
+        let dir = tempdir().unwrap();
+        add_repositories(dir.path(), &[
+            ("main", vec![entry("api", "1.2.0", "main-1.2.0"), entry("api", "2.0.0", "main-2.0.0")]),
+            ("mirror", vec![entry("api", "1.2.0", "mirror-1.2.0"), entry("api", "1.3.0", "mirror-1.3.0")]),
+        ]);
+
+        let resolve = |repository: Option<&str>, requirement: &str| {
+            let requirement = VersionReq::parse(requirement).unwrap();
+            resolve_remote(dir.path(), repository, "api", Some(&requirement)).map(|(remote, entry)| (remote.name, entry.urls[0].clone()))
+        };
+        assert_eq!(resolve(None, "^1").unwrap(), ("mirror".to_string(), "mirror-1.3.0".to_string()));
+        assert_eq!(resolve(None, "=1.2.0").unwrap(), ("main".to_string(), "main-1.2.0".to_string()));
+        assert_eq!(resolve(Some("main"), "^1").unwrap(), ("main".to_string(), "main-1.2.0".to_string()));
+        assert_eq!(resolve_remote(dir.path(), None, "api", None).unwrap().1.urls[0], "main-2.0.0");
+        assert_eq!(resolve(None, "^3").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(resolve(Some("other"), "^1").unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn skips_repositories_without_a_cached_index_unless_asked_for() {
+        let dir = tempdir().unwrap();
+        add_repositories(dir.path(), &[("stale", vec![]), ("main", vec![entry("api", "1.0.0", "main-1.0.0")])]);
+
+        assert_eq!(resolve_remote(dir.path(), None, "api", None).unwrap().0.name, "main");
+        let error = resolve_remote(dir.path(), Some("stale"), "api", None).unwrap_err();
+        assert!(error.to_string().contains("run `protypo repo update`"), "{}", error);
+    }
+}
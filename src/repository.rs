@@ -0,0 +1,254 @@
+use std::{fs, io};
+use std::path::{Path, PathBuf};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use crate::generator::GeneratorYaml;
+
+/// File in an installed generator recording where it was installed from.
+pub const INSTALL_METADATA: &str = ".protypo-install.yaml";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InstallMetadata {
+    /// the uri given to `protypo install`
+    #[serde(rename = "source")]
+    pub source: String,
+
+    /// the commit a git source was checked out at
+    #[serde(rename = "commit", skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+}
+
+/// Directory of the local repository where generators are installed as `<name>/<version>`.
+pub fn local_generators_dir() -> Result<PathBuf, io::Error> {
+    Ok(local_data_dir()?.join("generators"))
+}
+
+/// Directory for the configuration and data of protypo.
+pub fn local_data_dir() -> Result<PathBuf, io::Error> {
+    dirs::data_local_dir()
+        .map(|dir| dir.join("protypo"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Cannot determine the local data directory"))
+}
+
+/// Parses a version constraint such as `^1.2`, `~0.3` or `>=1, <2`.
+/// A plain version like `1.2.3` means exactly that version, unlike in cargo where it is a caret requirement.
+pub fn parse_version_req(requirement: &str) -> Result<VersionReq, io::Error> {
+    let requirement = requirement.trim();
+    let parsed = match Version::parse(requirement) {
+        Ok(version) => VersionReq::parse(&format!("={}", version)),
+        Err(_) => VersionReq::parse(requirement),
+    };
+    parsed.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid version constraint '{}': {}", requirement, e)))
+}
+
+/// Versions of the generator `name` installed in `generators_dir`, from the highest to the lowest.
+/// Directories whose name is not a semantic version are ignored.
+pub fn installed_versions(generators_dir: &Path, name: &str) -> Vec<Version> {
+    let mut versions: Vec<Version> = fs::read_dir(generators_dir.join(name))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| Version::parse(entry.file_name().to_str()?).ok())
+        .collect();
+    versions.sort_by(|a, b| b.cmp(a));
+    versions
+}
+
+/// Resolves the highest installed version of `name` matching `requirement`, or the latest one without a requirement.
+pub fn resolve_installed(generators_dir: &Path, name: &str, requirement: Option<&VersionReq>) -> Result<(Version, PathBuf), io::Error> {
+    let versions = installed_versions(generators_dir, name);
+    if versions.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Generator {} is not installed in {}", name, generators_dir.display())));
+    }
+
+    let version = versions.iter()
+        .find(|version| requirement.is_none_or(|requirement| requirement.matches(version)))
+        .ok_or_else(|| {
+            let available = versions.iter().map(Version::to_string).collect::<Vec<_>>().join(", ");
+            io::Error::new(io::ErrorKind::NotFound, format!("No installed version of generator {} matches {}, installed versions: {}",
+                name, requirement.map(VersionReq::to_string).unwrap_or_default(), available))
+        })?;
+    debug!("Resolved generator {} {:?} to version {}", name, requirement.map(VersionReq::to_string), version);
+    Ok((version.clone(), generators_dir.join(name).join(version.to_string())))
+}
+
+pub fn write_install_metadata(generator_dir: &Path, metadata: &InstallMetadata) -> Result<(), io::Error> {
+    let content = serde_yaml::to_string(metadata)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::write(generator_dir.join(INSTALL_METADATA), content)
+}
+
+/// A version of a generator installed in the local repository.
+#[derive(Debug, Clone)]
+pub struct InstalledGenerator {
+    pub version: Version,
+    pub generator_yaml: GeneratorYaml,
+}
+
+/// Every installed version of every generator in `generators_dir`, by name and from the highest to the lowest version.
+/// Versions whose `Generator.yaml` cannot be read are skipped.
+pub fn installed_generators(generators_dir: &Path) -> Vec<InstalledGenerator> {
+    let mut names: Vec<String> = fs::read_dir(generators_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+        .collect();
+    names.sort();
+
+    names.iter()
+        .flat_map(|name| installed_versions(generators_dir, name).into_iter().map(move |version| (name, version)))
+        .filter_map(|(name, version)| {
+            let path = generators_dir.join(name).join(version.to_string());
+            let generator_yaml = fs::read_to_string(path.join("Generator.yaml"))
+                .map_err(|e| e.to_string())
+                .and_then(|content| serde_yaml::from_str(&content).map_err(|e| e.to_string()));
+            match generator_yaml {
+                Ok(generator_yaml) => Some(InstalledGenerator { version, generator_yaml }),
+                Err(e) => {
+                    debug!("Skipping {}: {}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Splits a generator reference like `name@^1.2` into the name and the version part.
+pub fn split_generator_ref(reference: &str) -> (&str, Option<&str>) {
+    match reference.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (reference, None),
+    }
+}
+
+/// Whether `term` occurs, ignoring case, in the name, keywords, description or maintainers of a generator.
+pub fn matches_search(generator_yaml: &GeneratorYaml, term: &str) -> bool {
+    let term = term.to_lowercase();
+    let maintainers = generator_yaml.maintainers.iter()
+        .flatten()
+        .flat_map(|maintainer| [Some(&maintainer.name), maintainer.email.as_ref(), maintainer.url.as_ref()])
+        .flatten();
+    std::iter::once(&generator_yaml.name)
+        .chain(generator_yaml.keywords.iter().flatten())
+        .chain(generator_yaml.description.as_ref())
+        .chain(maintainers)
+        .any(|field| field.to_lowercase().contains(&term))
+}
+
+/// Removes one installed version of `name`, or all of them without a version.
+/// Returns the versions that were removed.
+pub fn uninstall(generators_dir: &Path, name: &str, version: Option<&Version>) -> Result<Vec<Version>, io::Error> {
+    if name.is_empty() || name.contains(['/', '\\', '@']) || name.starts_with('.') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid generator name '{}'", name)));
+    }
+    let installed = installed_versions(generators_dir, name);
+    let removed: Vec<Version> = match version {
+        Some(version) if installed.contains(version) => vec![version.clone()],
+        Some(version) => return Err(io::Error::new(io::ErrorKind::NotFound, format!("Generator {} {} is not installed", name, version))),
+        None if installed.is_empty() => return Err(io::Error::new(io::ErrorKind::NotFound, format!("Generator {} is not installed", name))),
+        None => installed,
+    };
+    for version in &removed {
+        let path = generators_dir.join(name).join(version.to_string());
+        debug!("Removing {}", path.display());
+        fs::remove_dir_all(&path)?;
+    }
+    if installed_versions(generators_dir, name).is_empty() {
+        fs::remove_dir_all(generators_dir.join(name))?;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn install(generators_dir: &Path, name: &str, version: &str, extra: &str) {
+        let dir = generators_dir.join(name).join(version);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Generator.yaml"), format!("apiVersion: v1\nname: {}\nversion: {}\n{}", name, version, extra)).unwrap();
+    }
+
+    fn generator_yaml(content: &str) -> GeneratorYaml {
+        serde_yaml::from_str(&format!("apiVersion: v1\nname: api\nversion: 1.0.0\n{}", content)).unwrap()
+    }
+
+    #[test]
+    fn splits_generator_references() {
+        assert_eq!(split_generator_ref("api"), ("api", None));
+        assert_eq!(split_generator_ref("api@^1.2"), ("api", Some("^1.2")));
+        assert_eq!(split_generator_ref("api@"), ("api", Some("")));
+    }
+
+    #[test]
+    fn searches_name_keywords_description_and_maintainers() {
+        let generator = generator_yaml("description: REST service\nkeywords: [Axum]\nmaintainers:\n- name: Ada\n  email: ada@example.com\n");
+
+        assert!(matches_search(&generator, "API"));
+        assert!(matches_search(&generator, "axum"));
+        assert!(matches_search(&generator, "rest"));
+        assert!(matches_search(&generator, "ada@example"));
+        assert!(!matches_search(&generator, "graphql"));
+    }
+
+    #[test]
+    fn lists_installed_versions_by_name_from_the_highest() {
+        let dir = tempfile::tempdir().unwrap();
+        install(dir.path(), "web", "1.0.0", "");
+        install(dir.path(), "api", "1.2.0", "");
+        install(dir.path(), "api", "1.10.0", "");
+        fs::create_dir_all(dir.path().join("api").join("latest")).unwrap();
+        fs::create_dir_all(dir.path().join("broken").join("1.0.0")).unwrap();
+
+        let listed: Vec<(String, String)> = installed_generators(dir.path()).iter()
+            .map(|installed| (installed.generator_yaml.name.clone(), installed.version.to_string()))
+            .collect();
+        assert_eq!(listed, [("api".to_string(), "1.10.0".to_string()), ("api".to_string(), "1.2.0".to_string()), ("web".to_string(), "1.0.0".to_string())]);
+    }
+
+    #[test]
+    fn resolves_the_highest_matching_version() {
+        let dir = tempfile::tempdir().unwrap();
+        for version in ["1.0.0", "1.4.0", "2.0.0"] {
+            install(dir.path(), "api", version, "");
+        }
+
+        let (version, path) = resolve_installed(dir.path(), "api", Some(&parse_version_req("^1").unwrap())).unwrap();
+        assert_eq!(version, Version::new(1, 4, 0));
+        assert_eq!(path, dir.path().join("api").join("1.4.0"));
+        assert_eq!(resolve_installed(dir.path(), "api", None).unwrap().0, Version::new(2, 0, 0));
+        assert_eq!(resolve_installed(dir.path(), "api", Some(&parse_version_req("1.0.0").unwrap())).unwrap().0, Version::new(1, 0, 0));
+        assert!(resolve_installed(dir.path(), "api", Some(&parse_version_req("^3").unwrap())).is_err());
+        assert!(resolve_installed(dir.path(), "web", None).is_err());
+    }
+
+    #[test]
+    fn uninstalls_one_or_all_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        install(dir.path(), "api", "1.0.0", "");
+        install(dir.path(), "api", "2.0.0", "");
+
+        assert_eq!(uninstall(dir.path(), "api", Some(&Version::new(1, 0, 0))).unwrap(), [Version::new(1, 0, 0)]);
+        assert_eq!(installed_versions(dir.path(), "api"), [Version::new(2, 0, 0)]);
+        assert_eq!(uninstall(dir.path(), "api", None).unwrap(), [Version::new(2, 0, 0)]);
+        assert!(!dir.path().join("api").exists());
+    }
+
+    #[test]
+    fn refuses_to_uninstall_outside_the_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let generators_dir = dir.path().join("generators");
+        install(dir.path(), "other", "1.0.0", "");
+        fs::create_dir_all(&generators_dir).unwrap();
+
+        for name in ["", "../other", "..", ".hidden", "a/b", "a\\b"] {
+            let error = uninstall(&generators_dir, name, None).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{}", name);
+        }
+        assert!(dir.path().join("other").join("1.0.0").exists());
+    }
+}
@@ -0,0 +1,351 @@
+use std::{fs, io};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+use crate::cache::read_cached;
+use crate::config::FetchPolicy;
+use crate::generator::GeneratorYaml;
+use crate::lock::bytes_digest;
+
+/// File in the local data directory with the public keys trusted to sign generators.
+pub const KEYRING_FILE: &str = "keyring.yaml";
+/// Directory in the local data directory with the private keys created with `protypo key generate`.
+const KEYS_DIR: &str = "keys";
+/// Extension of the provenance file written next to a package, e.g. `api-1.0.0.tar.gz.prov`.
+pub const PROVENANCE_EXTENSION: &str = "prov";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Keyring {
+    #[serde(rename = "keys", default)]
+    pub keys: Vec<TrustedKey>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrustedKey {
+    /// local name of the key
+    #[serde(rename = "name")]
+    pub name: String,
+
+    /// hex encoded ed25519 public key
+    #[serde(rename = "public_key")]
+    pub public_key: String,
+}
+
+/// Content of a provenance file: who signed which archive with which metadata.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Provenance {
+    /// the `Generator.yaml` of the package
+    #[serde(rename = "generator")]
+    pub generator: GeneratorYaml,
+
+    /// sha256 digest of the archive
+    #[serde(rename = "digest")]
+    pub digest: String,
+
+    /// hex encoded public key of the signer
+    #[serde(rename = "key")]
+    pub key: String,
+
+    /// hex encoded ed25519 signature over the digest and the metadata
+    #[serde(rename = "signature")]
+    pub signature: String,
+}
+
+/// What a provenance signature covers.
+#[derive(Serialize)]
+struct SignedContent<'a> {
+    digest: &'a str,
+    generator: &'a GeneratorYaml,
+}
+
+impl SignedContent<'_> {
+    fn to_bytes(&self) -> Result<Vec<u8>, io::Error> {
+        serde_json::to_vec(self).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+pub fn read_keyring(data_dir: &Path) -> Result<Keyring, io::Error> {
+    let path = data_dir.join(KEYRING_FILE);
+    if !path.is_file() {
+        return Ok(Keyring::default());
+    }
+    serde_yaml::from_str(&fs::read_to_string(&path)?)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("Cannot deserialize file {:?} due to error:{:?}", path, e)))
+}
+
+pub fn write_keyring(data_dir: &Path, keyring: &Keyring) -> Result<(), io::Error> {
+    let content = serde_yaml::to_string(keyring)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    fs::create_dir_all(data_dir)?;
+    fs::write(data_dir.join(KEYRING_FILE), content)
+}
+
+impl Keyring {
+    /// Adds a public key under `name`, refusing names and keys that are already in the keyring.
+    pub fn add(&mut self, name: &str, public_key: &str) -> Result<(), io::Error> {
+        let public_key = public_key.trim().to_lowercase();
+        parse_public_key(&public_key)?;
+        if let Some(existing) = self.keys.iter().find(|key| key.name == name || key.public_key == public_key) {
+            return Err(io::Error::new(ErrorKind::AlreadyExists, format!("Key {} is already in the keyring", existing.name)));
+        }
+        self.keys.push(TrustedKey { name: name.to_string(), public_key });
+        Ok(())
+    }
+}
+
+/// Creates a key pair, stores the private key in the local data directory and adds the public key to the keyring.
+/// Returns the hex encoded public key.
+pub fn generate_key(data_dir: &Path, name: &str) -> Result<String, io::Error> {
+    let key_path = private_key_path(data_dir, name)?;
+    if key_path.exists() {
+        return Err(io::Error::new(ErrorKind::AlreadyExists, format!("Key {} already exists in {}", name, key_path.display())));
+    }
+    let signing_key = SigningKey::from_bytes(&rand::random::<[u8; 32]>());
+    let public_key = hex::encode(signing_key.verifying_key().to_bytes());
+
+    let mut keyring = read_keyring(data_dir)?;
+    keyring.add(name, &public_key)?;
+    fs::create_dir_all(data_dir.join(KEYS_DIR))?;
+    write_private_key(&key_path, &hex::encode(signing_key.to_bytes()))?;
+    write_keyring(data_dir, &keyring)?;
+    info!("Created key {} in {}", name, key_path.display());
+    Ok(public_key)
+}
+
+fn private_key_path(data_dir: &Path, name: &str) -> Result<PathBuf, io::Error> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("Invalid key name '{}'", name)));
+    }
+    Ok(data_dir.join(KEYS_DIR).join(format!("{}.key", name)))
+}
+
+#[cfg(unix)]
+fn write_private_key(path: &Path, content: &str) -> Result<(), io::Error> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?
+        .write_all(content.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private_key(path: &Path, content: &str) -> Result<(), io::Error> {
+    fs::write(path, content)
+}
+
+fn read_private_key(data_dir: &Path, name: &str) -> Result<SigningKey, io::Error> {
+    let key_path = private_key_path(data_dir, name)?;
+    let content = fs::read_to_string(&key_path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot read private key {}: {}", key_path.display(), e)))?;
+    let bytes: [u8; 32] = hex::decode(content.trim()).ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, format!("Invalid private key in {}", key_path.display())))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+fn parse_public_key(public_key: &str) -> Result<VerifyingKey, io::Error> {
+    let bytes: [u8; 32] = hex::decode(public_key.trim()).ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("Invalid public key '{}', expected 64 hex characters", public_key)))?;
+    VerifyingKey::from_bytes(&bytes)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("Invalid public key '{}': {}", public_key, e)))
+}
+
+/// Signs a package with the private key `key_name` and writes its provenance file next to the archive.
+pub fn sign_package(data_dir: &Path, key_name: &str, archive_path: &Path, generator: &GeneratorYaml) -> Result<PathBuf, io::Error> {
+    let signing_key = read_private_key(data_dir, key_name)?;
+    let digest = bytes_digest(&fs::read(archive_path)?);
+    let signature = signing_key.sign(&SignedContent { digest: &digest, generator }.to_bytes()?);
+    let provenance = Provenance {
+        generator: generator.clone(),
+        digest,
+        key: hex::encode(signing_key.verifying_key().to_bytes()),
+        signature: hex::encode(signature.to_bytes()),
+    };
+    let provenance_path = provenance_path(archive_path);
+    let content = serde_yaml::to_string(&provenance)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    fs::write(&provenance_path, content)?;
+    Ok(provenance_path)
+}
+
+/// Path of the provenance file of the archive at `archive_path`.
+pub fn provenance_path(archive_path: &Path) -> PathBuf {
+    let mut path = archive_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(PROVENANCE_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Url of the provenance file published next to the archive at `archive_url`.
+pub fn provenance_url(archive_url: &Url) -> Url {
+    let mut url = archive_url.clone();
+    url.set_path(&format!("{}.{}", archive_url.path(), PROVENANCE_EXTENSION));
+    url
+}
+
+/// Verifies that `provenance` was signed by a key of the keyring for exactly this archive,
+/// and that the archive, extracted into `extracted_dir`, has the signed metadata.
+/// Returns the name of the key that signed the package.
+pub fn verify_package<'k>(keyring: &'k Keyring, archive: &[u8], extracted_dir: &Path, provenance: &[u8]) -> Result<&'k str, io::Error> {
+    let provenance: Provenance = serde_yaml::from_slice(provenance)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("Invalid provenance file: {}", e)))?;
+    let trusted_key = keyring.keys.iter()
+        .find(|key| key.public_key.eq_ignore_ascii_case(&provenance.key))
+        .ok_or_else(|| io::Error::new(ErrorKind::PermissionDenied, format!("Package is signed by key {} which is not in the keyring", provenance.key)))?;
+
+    let digest = bytes_digest(archive);
+    if digest != provenance.digest {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("Digest of the package {} does not match its provenance {}", digest, provenance.digest)));
+    }
+    let signature: [u8; 64] = hex::decode(&provenance.signature).ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Invalid signature in provenance file"))?;
+    parse_public_key(&trusted_key.public_key)?
+        .verify_strict(&SignedContent { digest: &provenance.digest, generator: &provenance.generator }.to_bytes()?, &Signature::from_bytes(&signature))
+        .map_err(|_| io::Error::new(ErrorKind::PermissionDenied, format!("Signature of the package does not match key {}", trusted_key.name)))?;
+
+    let content = fs::read_to_string(extracted_dir.join("Generator.yaml"))?;
+    let generator: GeneratorYaml = serde_yaml::from_str(&content)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    let signed = serde_json::to_value(&provenance.generator).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    let packaged = serde_json::to_value(&generator).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    if signed != packaged {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Generator.yaml of the package does not match its provenance"));
+    }
+    debug!("Package {} {} is signed by key {}", generator.name, generator.version, trusted_key.name);
+    Ok(&trusted_key.name)
+}
+
+/// Verifies the archive read from `archive_url` and extracted into `extracted_dir` against the provenance file
+/// published next to it, when the policy asks for signatures.
+pub async fn check_signature(policy: &FetchPolicy, archive_url: &Url, archive: &[u8], extracted_dir: &Path) -> Result<(), io::Error> {
+    if !policy.verify {
+        return Ok(());
+    }
+    let provenance_url = provenance_url(archive_url);
+    let provenance = read_cached(policy, &provenance_url).await
+        .map_err(|e| io::Error::new(ErrorKind::PermissionDenied, format!("Cannot verify {}, its provenance file {} cannot be read: {}", archive_url, provenance_url, e)))?;
+    let key = verify_package(&policy.keyring, archive, extracted_dir, &provenance)
+        .map_err(|e| io::Error::new(e.kind(), format!("Verification of {} failed: {}", archive_url, e)))?;
+    info!("Verified signature of {} by key {}", archive_url, key);
+    Ok(())
+}
+
+/// Git sources and plain directories carry no provenance, so they are refused when the policy asks for signatures.
+pub fn check_unsigned_source(policy: &FetchPolicy, source: &str) -> Result<(), io::Error> {
+    if policy.verify {
+        return Err(io::Error::new(ErrorKind::PermissionDenied, format!("Cannot verify {}, only packaged generators can be signed", source)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GENERATOR_YAML: &str = "apiVersion: v1\nname: web\nversion: 1.0.0\n";
+
+    /// A package signed with a fresh key: the data directory, the archive, the extracted generator and the provenance.
+    struct Signed {
+        dir: tempfile::TempDir,
+        archive: Vec<u8>,
+        provenance: Vec<u8>,
+    }
+
+    impl Signed {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            generate_key(dir.path(), "test").unwrap();
+            let archive = b"archive content".to_vec();
+            let archive_path = dir.path().join("web-1.0.0.tar.gz");
+            fs::write(&archive_path, &archive).unwrap();
+            let extracted = dir.path().join("extracted");
+            fs::create_dir(&extracted).unwrap();
+            fs::write(extracted.join("Generator.yaml"), GENERATOR_YAML).unwrap();
+            let generator: GeneratorYaml = serde_yaml::from_str(GENERATOR_YAML).unwrap();
+            let provenance = fs::read(sign_package(dir.path(), "test", &archive_path, &generator).unwrap()).unwrap();
+            Signed { dir, archive, provenance }
+        }
+
+        fn keyring(&self) -> Keyring {
+            read_keyring(self.dir.path()).unwrap()
+        }
+
+        fn verify(&self, keyring: &Keyring, archive: &[u8], provenance: &[u8]) -> Result<String, io::Error> {
+            verify_package(keyring, archive, &self.dir.path().join("extracted"), provenance).map(str::to_string)
+        }
+
+        fn edited_provenance(&self, edit: impl FnOnce(&mut Provenance)) -> Vec<u8> {
+            let mut provenance: Provenance = serde_yaml::from_slice(&self.provenance).unwrap();
+            edit(&mut provenance);
+            serde_yaml::to_string(&provenance).unwrap().into_bytes()
+        }
+    }
+
+    #[test]
+    fn accepts_a_signed_package() {
+        let signed = Signed::new();
+        assert_eq!(signed.verify(&signed.keyring(), &signed.archive, &signed.provenance).unwrap(), "test");
+    }
+
+    #[test]
+    fn rejects_a_key_outside_of_the_keyring() {
+        let signed = Signed::new();
+        let error = signed.verify(&Keyring::default(), &signed.archive, &signed.provenance).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn rejects_a_modified_archive() {
+        let signed = Signed::new();
+        let error = signed.verify(&signed.keyring(), b"other content", &signed.provenance).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_a_provenance_for_another_archive() {
+        let signed = Signed::new();
+        let digest = bytes_digest(b"other content");
+        let provenance = signed.edited_provenance(|provenance| provenance.digest = digest);
+        let error = signed.verify(&signed.keyring(), b"other content", &provenance).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn rejects_modified_signed_metadata() {
+        let signed = Signed::new();
+        let provenance = signed.edited_provenance(|provenance| provenance.generator.version = "2.0.0".to_string());
+        let error = signed.verify(&signed.keyring(), &signed.archive, &provenance).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn rejects_an_invalid_signature() {
+        let signed = Signed::new();
+        let provenance = signed.edited_provenance(|provenance| provenance.signature = "00".repeat(64));
+        let error = signed.verify(&signed.keyring(), &signed.archive, &provenance).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+
+        let provenance = signed.edited_provenance(|provenance| provenance.signature = "not hex".to_string());
+        let error = signed.verify(&signed.keyring(), &signed.archive, &provenance).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_a_package_whose_generator_differs_from_the_signed_one() {
+        let signed = Signed::new();
+        fs::write(signed.dir.path().join("extracted/Generator.yaml"), GENERATOR_YAML.replace("1.0.0", "1.0.1")).unwrap();
+        let error = signed.verify(&signed.keyring(), &signed.archive, &signed.provenance).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_an_invalid_provenance_file() {
+        let signed = Signed::new();
+        let error = signed.verify(&signed.keyring(), &signed.archive, b"not: [a provenance").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
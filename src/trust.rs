@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::{fs, io};
+use std::io::{ErrorKind, IsTerminal, Write};
+use std::path::Path;
+use reqwest::Url;
+use url::Host;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+use crate::config::{FetchPolicy, CONFIG_FILE};
+use crate::git::is_git_uri;
+use crate::lock::directory_digest;
+
+/// File in the local data directory with the trust decisions of the user, by digest of the generator.
+pub const TRUST_FILE: &str = "trust.yaml";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TrustStore {
+    #[serde(rename = "decisions", default)]
+    pub decisions: BTreeMap<String, TrustDecision>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrustDecision {
+    /// the source the generator was fetched from when the user decided
+    #[serde(rename = "source")]
+    pub source: String,
+
+    /// whether the user trusted the generator
+    #[serde(rename = "trusted")]
+    pub trusted: bool,
+}
+
+pub fn read_trust_store(data_dir: &Path) -> Result<TrustStore, io::Error> {
+    let path = data_dir.join(TRUST_FILE);
+    if !path.is_file() {
+        return Ok(TrustStore::default());
+    }
+    serde_yaml::from_str(&fs::read_to_string(&path)?)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("Cannot deserialize file {:?} due to error:{:?}", path, e)))
+}
+
+pub fn write_trust_store(data_dir: &Path, store: &TrustStore) -> Result<(), io::Error> {
+    let content = serde_yaml::to_string(store)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    fs::create_dir_all(data_dir)?;
+    fs::write(data_dir.join(TRUST_FILE), content)
+}
+
+/// Checks whether a generator may be fetched from `source` before anything is downloaded.
+/// Local paths and `file://` urls are always allowed. Remote sources must use `https`, or `ssh` for git,
+/// and must not be on a denied host. Plain `http` is only allowed from the allowed hosts and from loopback addresses. Returns whether the user has to confirm the generator once it is fetched,
+/// which is the case for every remote host that is not in the allowed hosts.
+pub fn check_source(policy: &FetchPolicy, source: &str) -> Result<bool, io::Error> {
+    if Path::new(source).exists() {
+        return Ok(false);
+    }
+    let url = Url::parse(source.strip_prefix("git+").unwrap_or(source))
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("Invalid source {}: {}", source, e)))?;
+    match url.scheme() {
+        "file" => return Ok(false),
+        "https" | "http" => {}
+        "ssh" if is_git_uri(source) => {}
+        scheme => return Err(io::Error::new(ErrorKind::PermissionDenied,
+            format!("Refusing {}, generators are only fetched from local files or over https, not {}", source, scheme))),
+    }
+    let host = url.host_str()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("Source {} has no host", source)))?;
+    if let Some(pattern) = policy.denied_hosts.iter().find(|pattern| host_matches(pattern, host)) {
+        return Err(io::Error::new(ErrorKind::PermissionDenied,
+            format!("Refusing {}, host {} is denied by '{}' in {}", source, host, pattern, CONFIG_FILE)));
+    }
+    let allowed = policy.allowed_hosts.iter().any(|pattern| host_matches(pattern, host));
+    if url.scheme() == "http" && !allowed && !is_loopback(&url) {
+        return Err(io::Error::new(ErrorKind::PermissionDenied,
+            format!("Refusing {}, http is only used for hosts in allowed_hosts in {} and loopback addresses", source, CONFIG_FILE)));
+    }
+    debug!("Host {} of {} is {}", host, source, if allowed { "allowed" } else { "not in the allowed hosts" });
+    Ok(!allowed)
+}
+
+/// Matches a host against a pattern of the user config, either the host itself or `*.<domain>` for its subdomains.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.len() > domain.len()
+            && host[..host.len() - domain.len()].ends_with('.')
+            && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+fn is_loopback(url: &Url) -> bool {
+    match url.host() {
+        Some(Host::Domain(domain)) => domain.eq_ignore_ascii_case("localhost"),
+        Some(Host::Ipv4(address)) => address.is_loopback(),
+        Some(Host::Ipv6(address)) => address.is_loopback(),
+        None => false,
+    }
+}
+
+/// Asks the user whether to trust the generator fetched from `source` into `generator_dir`, before any of its templates is executed.
+/// The decision is recorded by the digest of the generator, so the same content is only asked about once
+/// and a changed generator is asked about again.
+pub fn confirm_source(policy: &FetchPolicy, source: &str, generator_dir: &Path) -> Result<(), io::Error> {
+    let digest = directory_digest(generator_dir)?;
+    let mut store = read_trust_store(&policy.data_dir)?;
+    if let Some(decision) = store.decisions.get(&digest) {
+        debug!("Recorded decision for {} ({}): trusted={}", source, digest, decision.trusted);
+        return match decision.trusted {
+            true => Ok(()),
+            false => Err(io::Error::new(ErrorKind::PermissionDenied, format!("Refusing {}, it was not trusted before ({})", source, digest))),
+        };
+    }
+
+    if !io::stdin().is_terminal() {
+        return Err(io::Error::new(ErrorKind::PermissionDenied,
+            format!("Refusing {}, its host is not trusted. Add it to allowed_hosts in {} to use it without being asked", source, CONFIG_FILE)));
+    }
+    eprint!("Generator {} ({}) is not from a trusted host and its templates will be executed. Trust it? [y/N] ", source, digest);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let trusted = matches!(answer.trim().to_lowercase().as_str(), "y" | "yes");
+
+    record_decision(policy, &mut store, source, &digest, trusted)?;
+    match trusted {
+        true => Ok(()),
+        false => Err(io::Error::new(ErrorKind::PermissionDenied, format!("Refusing {}, it was not trusted", source))),
+    }
+}
+
+fn record_decision(policy: &FetchPolicy, store: &mut TrustStore, source: &str, digest: &str, trusted: bool) -> Result<(), io::Error> {
+    store.decisions.insert(digest.to_string(), TrustDecision { source: source.to_string(), trusted });
+    write_trust_store(&policy.data_dir, store)?;
+    info!("Recorded {} as {} for {}", digest, if trusted { "trusted" } else { "not trusted" }, source);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UserConfig;
+    use crate::signing::Keyring;
+
+    fn policy(data_dir: &Path, allowed_hosts: &[&str], denied_hosts: &[&str]) -> FetchPolicy {
+        let config = UserConfig {
+            allowed_hosts: allowed_hosts.iter().map(|host| host.to_string()).collect(),
+            denied_hosts: denied_hosts.iter().map(|host| host.to_string()).collect(),
+            ..UserConfig::default()
+        };
+        FetchPolicy::new(data_dir, &config, false, Keyring::default())
+    }
+
+    #[test]
+    fn matches_hosts_and_subdomains() {
+        assert!(host_matches("github.com", "GitHub.com"));
+        assert!(!host_matches("github.com", "api.github.com"));
+        assert!(host_matches("*.example.com", "api.example.com"));
+        assert!(host_matches("*.example.com", "a.b.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn allows_local_sources_without_consent() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = policy(dir.path(), &[], &[]);
+
+        assert!(!check_source(&policy, &dir.path().display().to_string()).unwrap());
+        assert!(!check_source(&policy, "file://generators/api").unwrap());
+    }
+
+    #[test]
+    fn asks_for_consent_unless_the_host_is_allowed() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = policy(dir.path(), &["*.example.com"], &[]);
+
+        assert!(!check_source(&policy, "https://generators.example.com/api.tar.gz").unwrap());
+        assert!(check_source(&policy, "https://example.org/api.tar.gz").unwrap());
+        assert!(check_source(&policy, "git+ssh://git@example.org/api.git").unwrap());
+    }
+
+    #[test]
+    fn denied_hosts_take_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = policy(dir.path(), &["*.example.com"], &["evil.example.com"]);
+
+        let error = check_source(&policy, "https://evil.example.com/api.tar.gz").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn allows_http_only_from_allowed_hosts_and_loopback() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = policy(dir.path(), &["mirror.internal"], &[]);
+
+        assert!(!check_source(&policy, "http://mirror.internal/api.tar.gz").unwrap());
+        assert!(check_source(&policy, "http://localhost:8080/api.tar.gz").unwrap());
+        assert!(check_source(&policy, "http://127.0.0.1/api.tar.gz").unwrap());
+        assert!(check_source(&policy, "http://[::1]/api.tar.gz").unwrap());
+        let error = check_source(&policy, "http://example.org/api.tar.gz").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert!(check_source(&policy, "ftp://example.org/api.tar.gz").is_err());
+    }
+
+    #[test]
+    fn uses_the_recorded_decision_for_the_same_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = policy(&dir.path().join("data"), &[], &[]);
+        let trusted = dir.path().join("trusted");
+        let distrusted = dir.path().join("distrusted");
+        for (generator, name) in [(&trusted, "trusted"), (&distrusted, "distrusted")] {
+            fs::create_dir_all(generator).unwrap();
+            fs::write(generator.join("Generator.yaml"), format!("name: {}\n", name)).unwrap();
+        }
+        let mut store = read_trust_store(&policy.data_dir).unwrap();
+        record_decision(&policy, &mut store, "https://example.org/trusted.tar.gz", &directory_digest(&trusted).unwrap(), true).unwrap();
+        record_decision(&policy, &mut store, "https://example.org/distrusted.tar.gz", &directory_digest(&distrusted).unwrap(), false).unwrap();
+
+        let recorded = read_trust_store(&policy.data_dir).unwrap();
+        assert_eq!(recorded.decisions.len(), 2);
+        assert!(recorded.decisions[&directory_digest(&trusted).unwrap()].trusted);
+        confirm_source(&policy, "https://mirror.example.org/trusted.tar.gz", &trusted).unwrap();
+        let error = confirm_source(&policy, "https://example.org/distrusted.tar.gz", &distrusted).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    }
+}
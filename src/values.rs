@@ -0,0 +1,560 @@
+use std::{fmt, fs, io};
+use anyhow::anyhow;
+use clap::ValueEnum;
+use json_value_merge::Merge;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tracing::debug;
+
+/// A single violation of a generator's `values.schema.json`.
+#[derive(Debug)]
+pub struct SchemaViolation {
+    /// key of the generator (`name:version`) whose schema was violated
+    pub generator: String,
+    /// JSON pointer to the offending value
+    pub pointer: String,
+    /// the schema keyword that failed, e.g. `type` or `required`
+    pub keyword: String,
+    pub message: String,
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pointer = if self.pointer.is_empty() { "/" } else { self.pointer.as_str() };
+        write!(f, "{} - {}: {} (keyword: {})", self.generator, pointer, self.message, self.keyword)
+    }
+}
+
+/// Validates `values` against `schema` and returns every violation found.
+/// Fails only when the schema itself cannot be compiled.
+pub fn validate_values(generator: &str, schema: &Value, values: &Value) -> Result<Vec<SchemaViolation>, io::Error> {
+    let validator = jsonschema::validator_for(schema)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{} - invalid values.schema.json: {}", generator, e)))?;
+
+    let violations = validator.iter_errors(values)
+        .map(|error| {
+            let schema_path = error.schema_path.to_string();
+            let keyword = schema_path.rsplit('/').next().unwrap_or_default().to_string();
+            SchemaViolation {
+                generator: generator.to_string(),
+                pointer: error.instance_path.to_string(),
+                keyword,
+                message: error.to_string(),
+            }
+        })
+        .collect();
+    Ok(violations)
+}
+
+/// Collects the `default` keywords of a JSON schema into a value with the shape the schema describes.
+/// Local `$ref`s (`#/definitions/...`, `#/$defs/...`) are followed, as are `allOf` subschemas.
+pub fn schema_defaults(schema: &Value) -> Option<Value> {
+    collect_defaults(schema, schema, 0)
+}
+
+/// Recursive `$ref`s would otherwise never end, so resolution stops at this depth.
+const MAX_SCHEMA_DEPTH: usize = 32;
+
+fn collect_defaults(schema: &Value, root: &Value, depth: usize) -> Option<Value> {
+    if depth > MAX_SCHEMA_DEPTH {
+        return None;
+    }
+    let object = schema.as_object()?;
+
+    if let Some(reference) = object.get("$ref").and_then(Value::as_str) {
+        let referenced = reference.strip_prefix('#').and_then(|pointer| root.pointer(pointer));
+        return match referenced {
+            Some(referenced) => collect_defaults(referenced, root, depth + 1),
+            None => {
+                debug!("Ignoring unresolvable $ref {} while collecting schema defaults", reference);
+                None
+            }
+        };
+    }
+
+    let mut defaults = object.get("default").cloned();
+
+    if let Some(properties) = object.get("properties").and_then(Value::as_object) {
+        let property_defaults: Map<String, Value> = properties.iter()
+            .filter_map(|(name, property)| Some((name.clone(), collect_defaults(property, root, depth + 1)?)))
+            .collect();
+        if !property_defaults.is_empty() {
+            let defaults = defaults.get_or_insert_with(|| json!({}));
+            fill_defaults(defaults, &Value::Object(property_defaults));
+        }
+    }
+
+    if let Some(subschemas) = object.get("allOf").and_then(Value::as_array) {
+        for subschema in subschemas {
+            if let Some(subschema_defaults) = collect_defaults(subschema, root, depth + 1) {
+                match defaults.as_mut() {
+                    Some(defaults) => fill_defaults(defaults, &subschema_defaults),
+                    None => defaults = Some(subschema_defaults),
+                }
+            }
+        }
+    }
+
+    defaults
+}
+
+/// Inserts every key of `defaults` that is missing from `values`, descending into nested objects.
+/// Values that are already set, including arrays, are never touched.
+pub fn fill_defaults(values: &mut Value, defaults: &Value) {
+    if let (Value::Object(values), Value::Object(defaults)) = (values, defaults) {
+        for (key, default) in defaults {
+            match values.get_mut(key) {
+                Some(value) => fill_defaults(value, default),
+                None => {
+                    values.insert(key.clone(), default.clone());
+                }
+            }
+        }
+    }
+}
+
+/// The flavours of `--set` accepted by `generate`, mirroring the ones of helm.
+#[derive(Debug, Clone, Copy)]
+pub enum SetKind {
+    /// `--set`: values are typed, `true`, `42`, `null` and `{a,b}` become a boolean, a number, null and a list
+    Typed,
+    /// `--set-string`: values are always strings
+    String,
+    /// `--set-json`: the value is parsed as JSON
+    Json,
+    /// `--set-file`: the value is a path whose content becomes the value
+    File,
+}
+
+impl SetKind {
+    fn flag(&self) -> &'static str {
+        match self {
+            SetKind::Typed => "--set",
+            SetKind::String => "--set-string",
+            SetKind::Json => "--set-json",
+            SetKind::File => "--set-file",
+        }
+    }
+}
+
+/// Highest array index accepted in a `--set` key, the same limit as helm's.
+const MAX_INDEX: usize = 65536;
+
+#[derive(Debug)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// Applies a single `--set` style argument to `values`.
+/// `--set` and `--set-string` accept several comma separated `key=value` pairs, the other kinds exactly one.
+/// Keys are dot separated paths where `\.` escapes a dot and `name[0]` addresses an array element.
+pub fn apply_set(values: &mut Value, arg: &str, kind: SetKind) -> Result<(), anyhow::Error> {
+    let invalid = |reason: String| anyhow!("invalid {} argument '{}': {}", kind.flag(), arg, reason);
+
+    let pairs = match kind {
+        SetKind::Typed | SetKind::String => split_unescaped(arg, ','),
+        SetKind::Json | SetKind::File => vec![arg.to_string()],
+    };
+
+    for pair in pairs {
+        let (key, raw) = split_key_value(&pair).ok_or_else(|| invalid("expected key=value".to_string()))?;
+        let path = parse_key(key).map_err(invalid)?;
+        let value = match kind {
+            SetKind::Typed => typed_value(raw),
+            SetKind::String => Value::String(unescape(raw)),
+            SetKind::Json => serde_json::from_str(raw).map_err(|e| invalid(format!("value is not valid JSON: {}", e)))?,
+            SetKind::File => {
+                let content = fs::read_to_string(raw).map_err(|e| invalid(format!("cannot read file {}: {}", raw, e)))?;
+                Value::String(content)
+            }
+        };
+        set_path(values, &path, value).map_err(invalid)?;
+    }
+    Ok(())
+}
+
+/// Splits `input` on every `separator` that is neither escaped with a backslash nor inside `{...}`.
+/// Escapes are kept, they are removed once keys and values are parsed.
+fn split_unescaped(input: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut escaped = false;
+    let mut braces = 0usize;
+    for c in input.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => braces += 1,
+            '}' => braces = braces.saturating_sub(1),
+            c if c == separator && braces == 0 => {
+                parts.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    parts.push(current);
+    parts
+}
+
+fn split_key_value(pair: &str) -> Option<(&str, &str)> {
+    let (key, value) = pair.split_once('=')?;
+    if key.is_empty() {
+        return None;
+    }
+    Some((key, value))
+}
+
+fn unescape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut escaped = false;
+    for c in input.chars() {
+        if !escaped && c == '\\' {
+            escaped = true;
+            continue;
+        }
+        escaped = false;
+        output.push(c);
+    }
+    output
+}
+
+fn parse_key(key: &str) -> Result<Vec<PathSegment>, String> {
+    let mut path = vec![];
+    for part in split_unescaped(key, '.') {
+        let mut escaped = false;
+        let bracket = part.char_indices().find_map(|(i, c)| {
+            let found = !escaped && c == '[';
+            escaped = !escaped && c == '\\';
+            found.then_some(i)
+        });
+        let (name, mut indices) = part.split_at(bracket.unwrap_or(part.len()));
+        if name.is_empty() {
+            return Err(format!("key '{}' contains an empty path segment", key));
+        }
+        path.push(PathSegment::Key(unescape(name)));
+
+        while !indices.is_empty() {
+            let close = indices.find(']').filter(|_| indices.starts_with('['))
+                .ok_or_else(|| format!("malformed array index in '{}'", part))?;
+            let index = indices[1..close].parse::<usize>()
+                .map_err(|_| format!("array index '{}' in '{}' is not a non-negative integer", &indices[1..close], part))?;
+            if index > MAX_INDEX {
+                return Err(format!("array index {} in '{}' is above the limit of {}", index, part, MAX_INDEX));
+            }
+            path.push(PathSegment::Index(index));
+            indices = &indices[close + 1..];
+        }
+    }
+    Ok(path)
+}
+
+fn typed_value(raw: &str) -> Value {
+    if raw.len() >= 2 && raw.starts_with('{') && raw.ends_with('}') {
+        let inner = &raw[1..raw.len() - 1];
+        if inner.is_empty() {
+            return json!([]);
+        }
+        return Value::Array(split_unescaped(inner, ',').iter().map(|item| typed_value(item)).collect());
+    }
+    match raw {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        "null" => Value::Null,
+        _ => match raw.parse::<i64>() {
+            Ok(number) => json!(number),
+            Err(_) => Value::String(unescape(raw)),
+        },
+    }
+}
+
+fn set_path(values: &mut Value, path: &[PathSegment], value: Value) -> Result<(), String> {
+    let Some((segment, rest)) = path.split_first() else {
+        *values = value;
+        return Ok(());
+    };
+    let child = match segment {
+        PathSegment::Key(key) => {
+            if !values.is_object() {
+                if !values.is_null() {
+                    return Err(format!("cannot set key '{}' on a value that is not a mapping", key));
+                }
+                *values = json!({});
+            }
+            values.as_object_mut().unwrap().entry(key.clone()).or_insert(Value::Null)
+        }
+        PathSegment::Index(index) => {
+            if !values.is_array() {
+                if !values.is_null() {
+                    return Err(format!("cannot set index [{}] on a value that is not a list", index));
+                }
+                *values = json!([]);
+            }
+            let list = values.as_array_mut().unwrap();
+            if list.len() <= *index {
+                list.resize(index + 1, Value::Null);
+            }
+            &mut list[*index]
+        }
+    };
+    set_path(child, rest, value)
+}
+
+/// How lists are combined when values are merged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ListMerge {
+    /// the list of the overriding values replaces the original one
+    #[default]
+    Replace,
+    /// the list of the overriding values is appended to the original one
+    Append,
+}
+
+/// Deep merges `overlay` into `base`. A `null` in `overlay` deletes the key from `base`.
+pub fn merge_values(base: &mut Value, overlay: &Value, list_merge: ListMerge) {
+    merge(base, overlay, list_merge, true);
+}
+
+/// Deep merges an overlay into another overlay, e.g. a values file over the previous one.
+/// Unlike [`merge_values`] `null`s are kept, so that they still delete keys once the
+/// overlays are merged into the values of a generator.
+pub fn layer_values(base: &mut Value, overlay: &Value, list_merge: ListMerge) {
+    merge(base, overlay, list_merge, false);
+}
+
+fn merge(base: &mut Value, overlay: &Value, list_merge: ListMerge, delete_nulls: bool) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                if value.is_null() && delete_nulls {
+                    base.remove(key);
+                    continue;
+                }
+                match base.get_mut(key) {
+                    Some(base_value) => merge(base_value, value, list_merge, delete_nulls),
+                    None => {
+                        let mut value = value.clone();
+                        if delete_nulls {
+                            remove_nulls(&mut value);
+                        }
+                        base.insert(key.clone(), value);
+                    }
+                }
+            }
+        }
+        (base @ Value::Array(_), overlay @ Value::Array(_)) if list_merge == ListMerge::Append => base.merge(overlay),
+        (base, overlay) => *base = overlay.clone(),
+    }
+}
+
+fn remove_nulls(value: &mut Value) {
+    if let Value::Object(object) = value {
+        object.retain(|_, value| !value.is_null());
+        object.values_mut().for_each(remove_nulls);
+    }
+}
+
+/// Looks up a dot separated path, e.g. `database.enabled`. An empty path is the value itself.
+pub fn value_at_path<'a>(values: &'a Value, path: &str) -> Option<&'a Value> {
+    let path = path.trim().trim_matches('.');
+    if path.is_empty() {
+        return Some(values);
+    }
+    path.split('.').try_fold(values, |value, key| match value {
+        Value::Array(list) => list.get(key.parse::<usize>().ok()?),
+        _ => value.get(key),
+    })
+}
+
+/// Wraps `value` in nested mappings along a dot separated path, e.g. `a.b` gives `{"a": {"b": value}}`.
+pub fn nest_at_path(path: &str, value: Value) -> Value {
+    let path = path.trim().trim_matches('.');
+    if path.is_empty() {
+        return value;
+    }
+    path.rsplit('.').fold(value, |value, key| json!({ key: value }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(arg: &str, kind: SetKind) -> Result<Value, anyhow::Error> {
+        let mut values = json!({});
+        apply_set(&mut values, arg, kind)?;
+        Ok(values)
+    }
+
+    #[test]
+    fn typed_values() {
+        let values = set("a=true,b=42,c=null,d=x,e={1,two}", SetKind::Typed).unwrap();
+        assert_eq!(values, json!({"a": true, "b": 42, "c": null, "d": "x", "e": [1, "two"]}));
+    }
+
+    #[test]
+    fn string_values_are_not_converted() {
+        let values = set("a=true,b=42", SetKind::String).unwrap();
+        assert_eq!(values, json!({"a": "true", "b": "42"}));
+    }
+
+    #[test]
+    fn nested_keys_and_indices() {
+        let values = set("app.ports[1]=443,list[0].name=x", SetKind::Typed).unwrap();
+        assert_eq!(values, json!({"app": {"ports": [null, 443]}, "list": [{"name": "x"}]}));
+    }
+
+    #[test]
+    fn escaped_separators() {
+        let values = set(r"a\.b=1\,2,c={x\,y}", SetKind::Typed).unwrap();
+        assert_eq!(values, json!({"a.b": "1,2", "c": ["x,y"]}));
+    }
+
+    #[test]
+    fn json_values() {
+        let values = set(r#"app.ports=[80,443]"#, SetKind::Json).unwrap();
+        assert_eq!(values, json!({"app": {"ports": [80, 443]}}));
+    }
+
+    #[test]
+    fn rejects_malformed_arguments() {
+        for arg in ["novalue", "=1", "a..b=1", "a[x]=1", "a[1=1", "a[-1]=1"] {
+            assert!(set(arg, SetKind::Typed).is_err(), "{} should be rejected", arg);
+        }
+    }
+
+    #[test]
+    fn rejects_indices_above_the_limit() {
+        assert!(set("a[65536]=x", SetKind::Typed).is_ok());
+        for arg in ["a[65537]=x", "a[4000000000]=x", "a[18446744073709551615]=x"] {
+            let error = set(arg, SetKind::Typed).unwrap_err().to_string();
+            assert!(error.contains(arg), "{} is not named in: {}", arg, error);
+        }
+    }
+
+    #[test]
+    fn rejects_keys_on_scalars() {
+        let mut values = json!({"a": 1});
+        assert!(apply_set(&mut values, "a.b=2", SetKind::Typed).is_err());
+        assert!(apply_set(&mut values, "a[0]=2", SetKind::Typed).is_err());
+    }
+
+    #[test]
+    fn merges_nested_objects() {
+        let mut values = json!({"app": {"name": "api", "port": 80}, "replicas": 1});
+        merge_values(&mut values, &json!({"app": {"port": 8080}, "debug": true}), ListMerge::Replace);
+        assert_eq!(values, json!({"app": {"name": "api", "port": 8080}, "replicas": 1, "debug": true}));
+    }
+
+    #[test]
+    fn null_deletes_a_key() {
+        let mut values = json!({"app": {"name": "api", "port": 80}, "replicas": 1});
+        merge_values(&mut values, &json!({"app": {"port": null}, "replicas": null, "new": {"a": null, "b": 1}}), ListMerge::Replace);
+        assert_eq!(values, json!({"app": {"name": "api"}, "new": {"b": 1}}));
+    }
+
+    #[test]
+    fn lists_are_replaced_or_appended() {
+        let mut replaced = json!({"tags": ["a", "b"]});
+        merge_values(&mut replaced, &json!({"tags": ["c"]}), ListMerge::Replace);
+        assert_eq!(replaced, json!({"tags": ["c"]}));
+
+        let mut appended = json!({"tags": ["a", "b"]});
+        merge_values(&mut appended, &json!({"tags": ["c"]}), ListMerge::Append);
+        assert_eq!(appended, json!({"tags": ["a", "b", "c"]}));
+    }
+
+    #[test]
+    fn layering_keeps_nulls_until_merged() {
+        let mut overrides = json!({"app": {"port": 8080}});
+        layer_values(&mut overrides, &json!({"app": {"debug": null}}), ListMerge::Replace);
+        assert_eq!(overrides, json!({"app": {"port": 8080, "debug": null}}));
+
+        let mut values = json!({"app": {"debug": true, "port": 80}});
+        merge_values(&mut values, &overrides, ListMerge::Replace);
+        assert_eq!(values, json!({"app": {"port": 8080}}));
+    }
+
+    #[test]
+    fn reports_every_violation_with_its_pointer_and_keyword() {
+        let schema = json!({"type": "object", "required": ["name"], "properties": {
+            "port": {"type": "integer"},
+            "tags": {"type": "array", "items": {"type": "string"}},
+        }});
+        let violations = validate_values("app:1.0.0", &schema, &json!({"port": "http", "tags": ["a", 1]})).unwrap();
+
+        let mut found: Vec<(&str, &str)> = violations.iter().map(|violation| (violation.pointer.as_str(), violation.keyword.as_str())).collect();
+        found.sort();
+        assert_eq!(found, [("", "required"), ("/port", "type"), ("/tags/1", "type")]);
+        assert!(violations.iter().all(|violation| violation.generator == "app:1.0.0"));
+        assert!(violations.iter().any(|violation| violation.to_string().starts_with("app:1.0.0 - /: ")));
+    }
+
+    #[test]
+    fn valid_values_have_no_violations() {
+        let schema = json!({"properties": {"port": {"type": "integer"}}});
+        assert!(validate_values("app:1.0.0", &schema, &json!({"port": 80})).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_schemas() {
+        let error = validate_values("app:1.0.0", &json!({"type": "nothing"}), &json!({})).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn collects_nested_defaults() {
+        let schema = json!({"type": "object", "properties": {
+            "app": {"type": "object", "properties": {"port": {"default": 8080}, "name": {"type": "string"}}},
+            "replicas": {"default": 1},
+        }});
+        assert_eq!(schema_defaults(&schema), Some(json!({"app": {"port": 8080}, "replicas": 1})));
+    }
+
+    #[test]
+    fn property_defaults_fill_an_object_default() {
+        let schema = json!({"properties": {"db": {"default": {"host": "db"}, "properties": {"host": {"default": "localhost"}, "port": {"default": 5432}}}}});
+        assert_eq!(schema_defaults(&schema), Some(json!({"db": {"host": "db", "port": 5432}})));
+    }
+
+    #[test]
+    fn follows_local_refs_and_all_of() {
+        let schema = json!({
+            "$defs": {"port": {"default": 80}},
+            "definitions": {"tls": {"properties": {"enabled": {"default": false}}}},
+            "properties": {
+                "http": {"$ref": "#/$defs/port"},
+                "server": {"allOf": [{"$ref": "#/definitions/tls"}, {"properties": {"workers": {"default": 4}}}]},
+                "remote": {"$ref": "https://example.com/schema.json"},
+            },
+        });
+        assert_eq!(schema_defaults(&schema), Some(json!({"http": 80, "server": {"enabled": false, "workers": 4}})));
+    }
+
+    #[test]
+    fn stops_at_recursive_refs() {
+        let schema = json!({
+            "$defs": {"node": {"properties": {"value": {"default": 0}, "child": {"$ref": "#/$defs/node"}}}},
+            "properties": {"tree": {"$ref": "#/$defs/node"}},
+        });
+        let defaults = schema_defaults(&schema).unwrap();
+
+        let mut depth = 0;
+        let mut node = &defaults["tree"];
+        while let Some(child) = node.get("child") {
+            assert_eq!(node["value"], 0);
+            node = child;
+            depth += 1;
+        }
+        assert!(depth > 0 && depth <= MAX_SCHEMA_DEPTH, "{}", depth);
+    }
+
+    #[test]
+    fn fills_only_missing_keys() {
+        let mut values = json!({"app": {"port": 9090, "tags": ["a"]}, "debug": null});
+        fill_defaults(&mut values, &json!({"app": {"port": 8080, "host": "0.0.0.0", "tags": ["b", "c"]}, "debug": true, "replicas": 1}));
+        assert_eq!(values, json!({"app": {"port": 9090, "host": "0.0.0.0", "tags": ["a"]}, "debug": null, "replicas": 1}));
+    }
+}